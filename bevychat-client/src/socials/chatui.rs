@@ -1,11 +1,25 @@
-use bevy::prelude::*;
+use std::collections::HashMap;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
 use bevy_egui::{
     EguiContexts, EguiPlugin, EguiPrimaryContextPass, EguiStartupSet,
     egui::{self, Align2, Color32, FontId, Layout, RichText},
 };
-use spacetimedb_sdk::Timestamp;
+use spacetimedb_sdk::{Identity, TimeDuration, Timestamp};
 
-use crate::socials::{ChatState, UserInfo, spacetime::ChatDataResource};
+use crate::{
+    module_bindings::{PresenceStatus, SanctionKind},
+    socials::{
+        ChatState, SpacetimeDB, UserInfo,
+        spacetime::{
+            BAN, CREATE_CHANNELS, ChannelsResource, ChatData, ChatDataResource, ChatNotice,
+            ChatTarget, ContactsResource, DELETE_ANY, MUTE, MentionToast, MessagePages,
+            UnreadCounts, UserCache, active_sanctions, conversation_members, has_permission,
+            last_read, linked_accounts, own_status, own_status_text, recent_audit_entries,
+            typing_users,
+        },
+    },
+};
 
 pub struct ChatUIPlugin;
 
@@ -15,6 +29,15 @@ impl Plugin for ChatUIPlugin {
             .insert_resource(UserAction::default())
             .add_event::<SendMessageEvent>()
             .add_event::<LoginEvent>()
+            .add_event::<ChannelEvent>()
+            .add_event::<MessageActionEvent>()
+            .add_event::<ReactionEvent>()
+            .add_event::<ModerationEvent>()
+            .add_event::<LoadOlderMessagesEvent>()
+            .add_event::<TypingEvent>()
+            .add_event::<StatusEvent>()
+            .add_event::<MarkReadEvent>()
+            .add_event::<UnlinkAccountEvent>()
             .add_systems(
                 PreStartup,
                 setup_camera_system.before(EguiStartupSet::InitContexts),
@@ -25,25 +48,152 @@ impl Plugin for ChatUIPlugin {
             )
             .add_systems(
                 EguiPrimaryContextPass,
-                show_main_window.run_if(in_state(ChatState::LoggedIn)),
-            );
+                show_discord_login_window.run_if(in_state(ChatState::AwaitingOAuth)),
+            )
+            .add_systems(
+                EguiPrimaryContextPass,
+                (
+                    show_main_window,
+                    show_member_list,
+                    show_profile_card,
+                    show_moderation_window,
+                    show_mention_toast,
+                )
+                    .run_if(in_state(ChatState::LoggedIn)),
+            )
+            .add_systems(Update, report_typing.run_if(in_state(ChatState::LoggedIn)));
     }
 }
 
+/// Emojis offered in the reaction picker of a message's context menu.
+const REACTION_PALETTE: [&str; 6] = ["👍", "❤", "😂", "😮", "😢", "🎉"];
+
 #[derive(Resource, Default, Clone)]
 pub struct UserAction {
     currently_typing: String,
+    active_chat: Option<ChatTarget>,
+    new_channel_name: String,
+    new_channel_private: bool,
+    /// The message whose text is currently being edited in the input field.
+    editing: Option<u64>,
+    /// The first message of the thread shown next to the chat window.
+    open_thread: Option<u64>,
+    thread_typing: String,
+    /// Whether the messages were scrolled all the way up last frame.
+    scrolled_to_top: bool,
+    /// The user picked in the moderation window.
+    moderation_target: Option<Identity>,
+    moderation_reason: String,
+    /// The open conversation and the last message read in it when it was opened,
+    /// after which the "new messages" divider is drawn.
+    unread_divider: Option<(ChatTarget, u64)>,
+    /// The custom status text being composed in the member list.
+    status_text: String,
+    /// The user whose profile card is open.
+    profile: Option<Identity>,
+}
+
+/// Event writers for everything the user can do from the chat window.
+#[derive(SystemParam)]
+struct ChatEvents<'w> {
+    send_msg: EventWriter<'w, SendMessageEvent>,
+    channels: EventWriter<'w, ChannelEvent>,
+    msg_actions: EventWriter<'w, MessageActionEvent>,
+    reactions: EventWriter<'w, ReactionEvent>,
+    load_older: EventWriter<'w, LoadOlderMessagesEvent>,
+    mark_read: EventWriter<'w, MarkReadEvent>,
+}
+
+/// What the local user may do with the messages of the open conversation.
+#[derive(Clone, Copy)]
+struct MessagePermissions {
+    local_identity: Option<Identity>,
+    in_channel: bool,
+    moderates: bool,
 }
 
 #[derive(Event)]
 pub struct SendMessageEvent {
+    pub target: ChatTarget,
     pub content: String,
+    /// The message whose thread this one replies to.
+    pub reply_to: Option<u64>,
+}
+
+#[derive(Event)]
+pub enum ChannelEvent {
+    Create { name: String, private: bool },
+    Join(u64),
+    Leave(u64),
+}
+
+#[derive(Event)]
+pub enum MessageActionEvent {
+    Edit { msg_id: u64, text: String },
+    Delete(u64),
+}
+
+#[derive(Event)]
+pub struct ReactionEvent {
+    pub msg_id: u64,
+    pub emoji: String,
+    /// Whether to add the reaction or take it back.
+    pub add: bool,
+}
+
+/// Sent whenever the message being composed in a channel changes.
+#[derive(Event)]
+pub struct TypingEvent {
+    pub channel_id: u64,
+    /// Whether there is any text left in the input field.
+    pub typing: bool,
+}
+
+/// Sent when the user has looked at a channel up to a message.
+#[derive(Event)]
+pub struct MarkReadEvent {
+    pub channel_id: u64,
+    pub message_id: u64,
+}
+
+#[derive(Event)]
+pub struct StatusEvent {
+    pub status: PresenceStatus,
+    pub text: Option<String>,
+}
+
+/// Asks to unlink the local user's account with `provider`.
+#[derive(Event)]
+pub struct UnlinkAccountEvent {
+    pub provider: String,
+}
+
+/// Requests the page of a channel's history before the oldest loaded message.
+#[derive(Event)]
+pub struct LoadOlderMessagesEvent(pub u64);
+
+#[derive(Event)]
+pub enum ModerationEvent {
+    Ban {
+        identity: Identity,
+        reason: String,
+        /// How long the ban lasts, or `None` to ban until lifted.
+        duration: Option<TimeDuration>,
+    },
+    Mute {
+        identity: Identity,
+        duration: TimeDuration,
+    },
+    Unban(Identity),
+    Unmute(Identity),
 }
 
 #[derive(Event)]
 pub enum LoginEvent {
     Username(String),
     Discord,
+    /// Gives up on a Discord login that hasn't finished yet.
+    CancelDiscord,
 }
 
 fn setup_camera_system(mut commands: Commands) {
@@ -62,6 +212,7 @@ fn show_login_window(
     mut contexts: EguiContexts,
     mut user_info: ResMut<UserInfo>,
     mut login: EventWriter<LoginEvent>,
+    notice: Res<ChatNotice>,
 ) -> Result {
     egui::Window::new("Login")
         .collapsible(false)
@@ -77,6 +228,9 @@ fn show_login_window(
                     login.write(LoginEvent::Username(user_info.username.clone()));
                 }
             });
+            if let Some(text) = &notice.text {
+                ui.colored_label(Color32::RED, text);
+            }
             if ui.add(egui::Button::new("Login with Discord")).clicked() {
                 login.write(LoginEvent::Discord);
             }
@@ -84,58 +238,908 @@ fn show_login_window(
     Ok(())
 }
 
+fn show_discord_login_window(
+    mut contexts: EguiContexts,
+    mut login: EventWriter<LoginEvent>,
+) -> Result {
+    egui::Window::new("Login")
+        .collapsible(false)
+        .anchor(Align2::CENTER_CENTER, [0., 0.])
+        .fixed_size([300.0, 200.0])
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Waiting for Discord");
+            });
+            ui.label(
+                RichText::new("Finish signing in with Discord in your browser.")
+                    .color(Color32::GRAY),
+            );
+            if ui.add(egui::Button::new("Cancel")).clicked() {
+                login.write(LoginEvent::CancelDiscord);
+            }
+        });
+    Ok(())
+}
+
 fn show_main_window(
     mut contexts: EguiContexts,
     mut action: ResMut<UserAction>,
-    mut send_msg: EventWriter<SendMessageEvent>,
+    mut events: ChatEvents,
     chat_data: Res<ChatDataResource>,
+    channels: Res<ChannelsResource>,
+    contacts: Res<ContactsResource>,
+    notice: Res<ChatNotice>,
+    pages: Res<MessagePages>,
+    unread: Res<UnreadCounts>,
+    users: Res<UserCache>,
+    stdb: SpacetimeDB,
 ) -> Result {
-    egui::Window::new("Chat Window")
+    // Fall back to the first joined channel when nothing (or a left channel) is selected.
+    let active_is_valid = match action.active_chat {
+        Some(ChatTarget::Channel(channel_id)) => channels
+            .joined
+            .iter()
+            .any(|channel| channel.id == channel_id),
+        Some(ChatTarget::Direct(_)) => true,
+        None => false,
+    };
+    if !active_is_valid {
+        action.active_chat = channels
+            .joined
+            .first()
+            .map(|channel| ChatTarget::Channel(channel.id));
+    }
+    let local_identity = stdb.try_identity();
+    let can_create_channels =
+        local_identity.is_some_and(|identity| has_permission(&stdb, identity, CREATE_CHANNELS));
+    let permissions = match action.active_chat {
+        Some(ChatTarget::Channel(_)) => MessagePermissions {
+            local_identity,
+            in_channel: true,
+            moderates: local_identity
+                .is_some_and(|identity| has_permission(&stdb, identity, DELETE_ANY)),
+        },
+        _ => MessagePermissions {
+            local_identity,
+            in_channel: false,
+            moderates: false,
+        },
+    };
+    // Markers may still be loading, so keep looking until the channel has one.
+    let divider_stale = match action.unread_divider {
+        Some((target, read_up_to)) => action.active_chat != Some(target) || read_up_to == 0,
+        None => action.active_chat.is_some(),
+    };
+    if divider_stale {
+        action.unread_divider = action.active_chat.map(|target| match target {
+            ChatTarget::Channel(channel_id) => (target, last_read(&stdb, channel_id)),
+            // Direct messages have no read markers.
+            ChatTarget::Direct(_) => (target, u64::MAX),
+        });
+    }
+    let unread_after = action.unread_divider.map_or(u64::MAX, |(_, msg_id)| msg_id);
+    let msgs = action
+        .active_chat
+        .and_then(|target| chat_data.msgs.get(&target));
+    if action.open_thread.is_some_and(|parent_id| {
+        !msgs.is_some_and(|msgs| msgs.iter().any(|msg| msg.msg_id == parent_id))
+    }) {
+        action.open_thread = None;
+    }
+    let ctx = contexts.ctx_mut()?;
+    let window = egui::Window::new("Chat Window")
         .title_bar(false)
         .anchor(Align2::RIGHT_BOTTOM, [-20.0, -20.0])
         .fixed_size([700.0, 300.0])
-        .show(contexts.ctx_mut()?, |ui| {
-            egui::ScrollArea::vertical().show(ui, |ui| {
-                for msg in &chat_data.msgs {
-                    ui.horizontal(|ui| {
-                        ui.with_layout(Layout::left_to_right(egui::Align::LEFT), |ui| {
-                            ui.label(
-                                RichText::new(format!(
-                                    "{} : {}",
-                                    msg.sender_username, msg.msg_text
-                                ))
-                                .font(FontId::proportional(14.0))
-                                .color(Color32::WHITE),
-                            );
-                        });
-                        ui.with_layout(Layout::right_to_left(egui::Align::RIGHT), |ui| {
+        .show(ctx, |ui| {
+            egui::SidePanel::left("channel_list")
+                .resizable(false)
+                .exact_width(150.0)
+                .show_inside(ui, |ui| {
+                    show_channel_list(
+                        ui,
+                        &mut action,
+                        &mut events.channels,
+                        &channels,
+                        &unread.0,
+                        &contacts,
+                        can_create_channels,
+                    );
+                });
+            let Some(target) = action.active_chat else {
+                ui.label("Join a channel to start chatting");
+                return;
+            };
+            let scroll = egui::ScrollArea::vertical().show(ui, |ui| {
+                if matches!(target, ChatTarget::Channel(channel_id) if !pages.has_older(channel_id))
+                {
+                    ui.label(
+                        RichText::new("This is the beginning of the channel")
+                            .font(FontId::proportional(12.0)),
+                    );
+                }
+                let mut divider_drawn = false;
+                for msg in msgs.into_iter().flatten() {
+                    // Replies are only shown in the thread of the message they reply to.
+                    if msg.reply_to.is_some() {
+                        continue;
+                    }
+                    if !divider_drawn
+                        && msg.msg_id > unread_after
+                        && Some(msg.sender) != local_identity
+                    {
+                        ui.horizontal(|ui| {
                             ui.label(
-                                RichText::new(format!("{}", get_formatted_time(msg.timestamp)))
+                                RichText::new("New messages")
                                     .font(FontId::proportional(12.0))
-                                    .color(Color32::GRAY),
+                                    .color(Color32::LIGHT_RED),
                             );
+                            ui.separator();
                         });
+                        divider_drawn = true;
+                    }
+                    let mentions_me = chat_data.mentions.contains(&msg.msg_id);
+                    show_message(
+                        ui,
+                        msg,
+                        mentions_me,
+                        &users,
+                        permissions,
+                        &mut action,
+                        &mut events,
+                    );
+                    let reply_count = chat_data.reply_count(msg.msg_id);
+                    if reply_count > 0
+                        && ui
+                            .link(
+                                RichText::new(format!("{} replies", reply_count))
+                                    .font(FontId::proportional(12.0)),
+                            )
+                            .clicked()
+                    {
+                        action.open_thread = Some(msg.msg_id);
+                    }
+                }
+            });
+            // Load the previous page of history once the user scrolls up to the oldest message.
+            let scrolled_to_top =
+                scroll.state.offset.y <= 0.0 && scroll.content_size.y > scroll.inner_rect.height();
+            let reached_top = scrolled_to_top && !action.scrolled_to_top;
+            if let (true, ChatTarget::Channel(channel_id)) = (reached_top, target) {
+                events.load_older.write(LoadOlderMessagesEvent(channel_id));
+            }
+            action.scrolled_to_top = scrolled_to_top;
+            ui.add_space(10.0);
+            ui.with_layout(Layout::bottom_up(egui::Align::LEFT), |ui| {
+                let submit_label = if action.editing.is_some() {
+                    "Save"
+                } else {
+                    "Send"
+                };
+                ui.horizontal(|ui| {
+                    if chat_input(ui, &mut action.currently_typing, submit_label) {
+                        if let Some(msg_id) = action.editing.take() {
+                            events.msg_actions.write(MessageActionEvent::Edit {
+                                msg_id,
+                                text: action.currently_typing.clone(),
+                            });
+                        } else {
+                            events.send_msg.write(SendMessageEvent {
+                                target,
+                                content: action.currently_typing.clone(),
+                                reply_to: None,
+                            });
+                        }
+                        action.currently_typing.clear();
+                    }
+                    if action.editing.is_some() && ui.button("Cancel").clicked() {
+                        action.editing = None;
+                        action.currently_typing.clear();
+                    }
+                });
+                let suggestions = mention_suggestions(&action.currently_typing, &contacts);
+                if !suggestions.is_empty() {
+                    ui.horizontal(|ui| {
+                        for name in suggestions {
+                            if ui.small_button(format!("@{}", name)).clicked() {
+                                complete_mention(&mut action.currently_typing, &name);
+                            }
+                        }
                     });
                 }
+                if let Some(text) = &notice.text {
+                    ui.colored_label(Color32::RED, text);
+                }
+                if let ChatTarget::Channel(channel_id) = target {
+                    let typing: Vec<_> = typing_users(&stdb, channel_id)
+                        .into_iter()
+                        .map(|identity| users.name(identity))
+                        .collect();
+                    if let Some(text) = describe_typing(&typing) {
+                        ui.label(
+                            RichText::new(text)
+                                .font(FontId::proportional(12.0))
+                                .color(Color32::GRAY),
+                        );
+                    }
+                }
+            });
+        });
+    // Everything loaded in the open channel counts as read while the user points at the chat.
+    let looking = window.is_some_and(|window| window.response.contains_pointer());
+    let newest = msgs.and_then(|msgs| msgs.back()).map(|msg| msg.msg_id);
+    if let (true, Some(ChatTarget::Channel(channel_id)), Some(message_id)) =
+        (looking, action.active_chat, newest)
+    {
+        events.mark_read.write(MarkReadEvent {
+            channel_id,
+            message_id,
+        });
+    }
+    let (Some(parent_id), Some(target)) = (action.open_thread, action.active_chat) else {
+        return Ok(());
+    };
+    egui::Window::new("Thread")
+        .collapsible(false)
+        .anchor(Align2::RIGHT_BOTTOM, [-740.0, -20.0])
+        .fixed_size([350.0, 300.0])
+        .show(ctx, |ui| {
+            if ui.button("Close").clicked() {
+                action.open_thread = None;
+            }
+            ui.separator();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let thread = msgs
+                    .into_iter()
+                    .flatten()
+                    .filter(|msg| msg.msg_id == parent_id || msg.reply_to == Some(parent_id));
+                for msg in thread {
+                    let mentions_me = chat_data.mentions.contains(&msg.msg_id);
+                    show_message(
+                        ui,
+                        msg,
+                        mentions_me,
+                        &users,
+                        permissions,
+                        &mut action,
+                        &mut events,
+                    );
+                }
             });
             ui.add_space(10.0);
             ui.with_layout(Layout::bottom_up(egui::Align::LEFT), |ui| {
                 ui.horizontal(|ui| {
-                    let response = ui.text_edit_singleline(&mut action.currently_typing);
-                    if ui.add(egui::Button::new("Send")).clicked()
-                        || response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))
-                    {
-                        send_msg.write(SendMessageEvent {
-                            content: action.currently_typing.clone(),
+                    if chat_input(ui, &mut action.thread_typing, "Reply") {
+                        events.send_msg.write(SendMessageEvent {
+                            target,
+                            content: std::mem::take(&mut action.thread_typing),
+                            reply_to: Some(parent_id),
                         });
-                        action.currently_typing.clear();
                     }
                 });
-            })
+            });
         });
     Ok(())
 }
 
+/// Lists who takes part in the open conversation with their presence,
+/// along with controls for the local user's own status.
+fn show_member_list(
+    mut contexts: EguiContexts,
+    mut action: ResMut<UserAction>,
+    mut status_events: EventWriter<StatusEvent>,
+    users: Res<UserCache>,
+    stdb: SpacetimeDB,
+) -> Result {
+    egui::Window::new("Members")
+        .anchor(Align2::RIGHT_TOP, [-20.0, 20.0])
+        .fixed_size([220.0, 300.0])
+        .show(contexts.ctx_mut()?, |ui| {
+            let current = own_status(&stdb);
+            let mut status = current;
+            egui::ComboBox::from_label("Status")
+                .selected_text(status_label(status))
+                .show_ui(ui, |ui| {
+                    for option in [
+                        PresenceStatus::Online,
+                        PresenceStatus::Away,
+                        PresenceStatus::DoNotDisturb,
+                        PresenceStatus::Invisible,
+                    ] {
+                        ui.selectable_value(&mut status, option, status_label(option));
+                    }
+                });
+            ui.horizontal(|ui| {
+                let submitted = chat_input(ui, &mut action.status_text, "Set");
+                if submitted {
+                    let text = action.status_text.trim();
+                    status_events.write(StatusEvent {
+                        status,
+                        text: (!text.is_empty()).then(|| text.to_string()),
+                    });
+                } else if status != current {
+                    // Picking a status keeps the status text that was set before.
+                    let text = own_status_text(&stdb);
+                    status_events.write(StatusEvent { status, text });
+                }
+            });
+            ui.separator();
+            let Some(target) = action.active_chat else {
+                return;
+            };
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for user in conversation_members(&stdb, target) {
+                    ui.horizontal(|ui| {
+                        let (dot, color) = match (user.online, user.status) {
+                            (false, _) => ("○", Color32::GRAY),
+                            (true, PresenceStatus::Away) => ("●", Color32::YELLOW),
+                            (true, PresenceStatus::DoNotDisturb) => ("●", Color32::RED),
+                            (true, _) => ("●", Color32::GREEN),
+                        };
+                        ui.label(RichText::new(dot).color(color))
+                            .on_hover_text(if user.online {
+                                status_label(user.status)
+                            } else {
+                                "Offline"
+                            });
+                        let name = RichText::new(users.name(user.identity));
+                        let name = if user.online {
+                            name
+                        } else {
+                            name.color(Color32::GRAY)
+                        };
+                        if ui
+                            .add(egui::Label::new(name).sense(egui::Sense::click()))
+                            .on_hover_text("View profile")
+                            .clicked()
+                        {
+                            action.profile = Some(user.identity);
+                        }
+                    });
+                    let detail = match (&user.status_text, user.online) {
+                        (Some(text), true) => Some(text.clone()),
+                        (_, false) => Some(format_last_seen(user.last_seen)),
+                        (None, true) => None,
+                    };
+                    if let Some(detail) = detail {
+                        ui.label(
+                            RichText::new(detail)
+                                .font(FontId::proportional(12.0))
+                                .color(Color32::GRAY),
+                        );
+                    }
+                }
+            });
+        });
+    Ok(())
+}
+
+/// Shows who a user is and the accounts they linked with other providers,
+/// which the local user can unlink from their own card.
+fn show_profile_card(
+    mut contexts: EguiContexts,
+    mut action: ResMut<UserAction>,
+    mut unlink_events: EventWriter<UnlinkAccountEvent>,
+    users: Res<UserCache>,
+    stdb: SpacetimeDB,
+) -> Result {
+    let Some(identity) = action.profile else {
+        return Ok(());
+    };
+    let is_own = Some(identity) == stdb.try_identity();
+    let mut open = true;
+    egui::Window::new("Profile")
+        .open(&mut open)
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.label(
+                RichText::new(users.name(identity))
+                    .font(FontId::proportional(18.0))
+                    .strong(),
+            );
+            if users.is_verified(identity) {
+                ui.label(
+                    RichText::new("✔ Verified via Discord").color(Color32::from_rgb(88, 101, 242)),
+                );
+            }
+            ui.separator();
+            let accounts = linked_accounts(&stdb, identity);
+            if accounts.is_empty() {
+                ui.label(RichText::new("No linked accounts").color(Color32::GRAY));
+            }
+            for account in accounts {
+                ui.horizontal(|ui| {
+                    ui.label(RichText::new(provider_label(&account.provider)).strong());
+                    ui.label(account.display_name.as_str());
+                    ui.label(
+                        RichText::new(format!("since {}", get_formatted_date(account.linked_at)))
+                            .font(FontId::proportional(12.0))
+                            .color(Color32::GRAY),
+                    );
+                    if is_own && ui.small_button("Unlink").clicked() {
+                        unlink_events.write(UnlinkAccountEvent {
+                            provider: account.provider.clone(),
+                        });
+                    }
+                });
+            }
+        });
+    if !open {
+        action.profile = None;
+    }
+    Ok(())
+}
+
+/// The name of a provider as shown to users, e.g. "Discord" for "discord".
+fn provider_label(provider: &str) -> String {
+    let mut chars = provider.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn status_label(status: PresenceStatus) -> &'static str {
+    match status {
+        PresenceStatus::Online => "Online",
+        PresenceStatus::Away => "Away",
+        PresenceStatus::DoNotDisturb => "Do not disturb",
+        PresenceStatus::Invisible => "Invisible",
+    }
+}
+
+/// Describes how long ago an offline user was last seen, e.g. "last seen 5 min ago".
+fn format_last_seen(last_seen: Timestamp) -> String {
+    let minutes = Timestamp::now()
+        .time_duration_since(last_seen)
+        .map_or(0, |since| since.to_micros() / 60_000_000);
+    match minutes {
+        0 => "last seen just now".to_string(),
+        1..60 => format!("last seen {} min ago", minutes),
+        60..1440 => format!("last seen {} h ago", minutes / 60),
+        _ => format!("last seen {} d ago", minutes / 1440),
+    }
+}
+
+/// Shows the moderation tools, but only to users whose roles allow muting or banning.
+fn show_moderation_window(
+    mut contexts: EguiContexts,
+    mut action: ResMut<UserAction>,
+    mut moderation: EventWriter<ModerationEvent>,
+    users: Res<UserCache>,
+    stdb: SpacetimeDB,
+) -> Result {
+    let Some(local_identity) = stdb.try_identity() else {
+        return Ok(());
+    };
+    let can_mute = has_permission(&stdb, local_identity, MUTE);
+    let can_ban = has_permission(&stdb, local_identity, BAN);
+    if !can_mute && !can_ban {
+        return Ok(());
+    }
+    egui::Window::new("Moderation")
+        .default_open(false)
+        .anchor(Align2::LEFT_TOP, [20.0, 20.0])
+        .fixed_size([300.0, 200.0])
+        .show(contexts.ctx_mut()?, |ui| {
+            let selected_name = action.moderation_target.map_or_else(
+                || "Pick a user".to_string(),
+                |identity| users.name(identity),
+            );
+            // Guests are listed too, since they can be just as disruptive as named users.
+            egui::ComboBox::from_label("User")
+                .selected_text(selected_name)
+                .show_ui(ui, |ui| {
+                    for (identity, name) in users.all() {
+                        if identity != local_identity {
+                            ui.selectable_value(
+                                &mut action.moderation_target,
+                                Some(identity),
+                                name,
+                            );
+                        }
+                    }
+                });
+            let Some(target) = action.moderation_target else {
+                return;
+            };
+            let bans = active_sanctions(&stdb, target, SanctionKind::Ban);
+            let mutes = active_sanctions(&stdb, target, SanctionKind::Mute);
+            for sanction in bans.iter().chain(&mutes) {
+                let until = sanction.until.map_or("until lifted".to_string(), |until| {
+                    format!("until {}", get_formatted_time(until))
+                });
+                let reason = if sanction.reason.is_empty() {
+                    String::new()
+                } else {
+                    format!(": {}", sanction.reason)
+                };
+                ui.label(format!("{:?} {}{}", sanction.kind, until, reason));
+            }
+            ui.separator();
+            if can_mute {
+                ui.horizontal(|ui| {
+                    for (label, minutes) in [("Mute 10 min", 10), ("Mute 1 h", 60)] {
+                        if ui.button(label).clicked() {
+                            moderation.write(ModerationEvent::Mute {
+                                identity: target,
+                                duration: TimeDuration::from_micros(minutes * 60_000_000),
+                            });
+                        }
+                    }
+                    if !mutes.is_empty() && ui.button("Unmute").clicked() {
+                        moderation.write(ModerationEvent::Unmute(target));
+                    }
+                });
+            }
+            if can_ban {
+                ui.horizontal(|ui| {
+                    ui.label("Reason");
+                    ui.text_edit_singleline(&mut action.moderation_reason);
+                });
+                ui.horizontal(|ui| {
+                    let day = TimeDuration::from_micros(24 * 3_600_000_000);
+                    for (label, duration) in [("Ban 1 day", Some(day)), ("Ban", None)] {
+                        if ui.button(label).clicked() {
+                            moderation.write(ModerationEvent::Ban {
+                                identity: target,
+                                reason: std::mem::take(&mut action.moderation_reason),
+                                duration,
+                            });
+                        }
+                    }
+                    if !bans.is_empty() && ui.button("Unban").clicked() {
+                        moderation.write(ModerationEvent::Unban(target));
+                    }
+                });
+            }
+        });
+    egui::Window::new("Audit log")
+        .default_open(false)
+        .anchor(Align2::LEFT_TOP, [20.0, 260.0])
+        .fixed_size([300.0, 200.0])
+        .show(contexts.ctx_mut()?, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for entry in recent_audit_entries(&stdb, 50) {
+                    let target = entry
+                        .target
+                        .map(|target| format!(" {}", users.name(target)))
+                        .unwrap_or_default();
+                    ui.label(format!(
+                        "{} {} {:?}{}",
+                        get_formatted_time(entry.at),
+                        users.name(entry.actor),
+                        entry.action,
+                        target
+                    ));
+                    let mut details = entry.details;
+                    if !entry.reason.is_empty() {
+                        details.push_str(&format!(" ({})", entry.reason));
+                    }
+                    if !details.is_empty() {
+                        ui.label(RichText::new(details).font(FontId::proportional(12.0)));
+                    }
+                }
+            });
+        });
+    Ok(())
+}
+
+fn show_mention_toast(mut contexts: EguiContexts, toast: Res<MentionToast>) -> Result {
+    let Some(text) = &toast.text else {
+        return Ok(());
+    };
+    egui::Area::new(egui::Id::new("mention_toast"))
+        .anchor(Align2::CENTER_TOP, [0.0, 20.0])
+        .show(contexts.ctx_mut()?, |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.label(RichText::new(text).color(Color32::GOLD));
+            });
+        });
+    Ok(())
+}
+
+/// Where the `@name` being typed at the end of `text` starts, if the user is typing one.
+fn mention_start(text: &str) -> Option<usize> {
+    let at = text.rfind('@')?;
+    let starts_word = text[..at]
+        .chars()
+        .next_back()
+        .is_none_or(char::is_whitespace);
+    starts_word.then_some(at)
+}
+
+/// Names of users matching the `@name` being typed, for the user to pick from.
+fn mention_suggestions(text: &str, contacts: &ContactsResource) -> Vec<String> {
+    const MAX_SUGGESTIONS: usize = 5;
+    let Some(at) = mention_start(text) else {
+        return Vec::new();
+    };
+    let query = text[at + 1..].to_lowercase();
+    contacts
+        .users
+        .iter()
+        .filter_map(|user| user.name.clone())
+        .filter(|name| {
+            let name = name.to_lowercase();
+            name.starts_with(&query) && name != query
+        })
+        .take(MAX_SUGGESTIONS)
+        .collect()
+}
+
+/// Replaces the `@name` being typed with the picked name.
+fn complete_mention(text: &mut String, name: &str) {
+    if let Some(at) = mention_start(text) {
+        text.truncate(at + 1);
+        text.push_str(name);
+        text.push(' ');
+    }
+}
+
+/// Reports every change to the message being composed in a channel.
+fn report_typing(
+    action: Res<UserAction>,
+    mut last_typed: Local<String>,
+    mut typing: EventWriter<TypingEvent>,
+) {
+    if action.currently_typing == *last_typed {
+        return;
+    }
+    last_typed.clone_from(&action.currently_typing);
+    // Editing an existing message isn't shown as typing.
+    if let (Some(ChatTarget::Channel(channel_id)), None) = (action.active_chat, action.editing) {
+        typing.write(TypingEvent {
+            channel_id,
+            typing: !action.currently_typing.trim().is_empty(),
+        });
+    }
+}
+
+/// Phrases who is typing, such as "alice and bob are typing…".
+fn describe_typing(names: &[String]) -> Option<String> {
+    match names {
+        [] => None,
+        [name] => Some(format!("{} is typing…", name)),
+        [first, second] => Some(format!("{} and {} are typing…", first, second)),
+        [first, second, third] => Some(format!("{}, {} and {} are typing…", first, second, third)),
+        _ => Some("Several people are typing…".to_string()),
+    }
+}
+
+/// Renders a text field with a submit button, returning whether the user submitted it.
+fn chat_input(ui: &mut egui::Ui, text: &mut String, submit_label: &str) -> bool {
+    let response = ui.text_edit_singleline(text);
+    ui.add(egui::Button::new(submit_label)).clicked()
+        || response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter))
+}
+
+/// Renders a single message with its reactions and context menu.
+fn show_message(
+    ui: &mut egui::Ui,
+    msg: &ChatData,
+    mentions_me: bool,
+    users: &UserCache,
+    permissions: MessagePermissions,
+    action: &mut UserAction,
+    events: &mut ChatEvents,
+) {
+    let is_own = Some(msg.sender) == permissions.local_identity;
+    let can_react = permissions.in_channel && !msg.deleted;
+    let can_modify = can_react && (is_own || permissions.moderates);
+    ui.horizontal(|ui| {
+        ui.with_layout(Layout::left_to_right(egui::Align::LEFT), |ui| {
+            let sender = users.name(msg.sender);
+            if users.is_guest(msg.sender) {
+                ui.label(
+                    RichText::new("guest")
+                        .font(FontId::proportional(12.0))
+                        .italics()
+                        .color(Color32::LIGHT_BLUE),
+                )
+                .on_hover_text("This user hasn't set a name");
+            }
+            if users.is_service(msg.sender) {
+                ui.label(
+                    RichText::new("system")
+                        .font(FontId::proportional(12.0))
+                        .italics()
+                        .color(Color32::LIGHT_GREEN),
+                )
+                .on_hover_text("Posted by a trusted service");
+            }
+            if users.is_verified(msg.sender) {
+                ui.label(
+                    RichText::new("✔ Discord")
+                        .font(FontId::proportional(12.0))
+                        .color(Color32::from_rgb(88, 101, 242)),
+                )
+                .on_hover_text("Verified via Discord");
+            }
+            if let Some(old_name) = users.renamed_from(msg.sender, msg.timestamp) {
+                ui.label(
+                    RichText::new(format!("(renamed from {})", old_name))
+                        .font(FontId::proportional(12.0))
+                        .color(Color32::GRAY),
+                );
+            }
+            let text = if msg.deleted {
+                RichText::new(format!("{} : message deleted", sender))
+                    .italics()
+                    .color(Color32::GRAY)
+            } else if mentions_me {
+                RichText::new(format!("{} : {}", sender, msg.msg_text))
+                    .color(Color32::GOLD)
+                    .background_color(Color32::from_rgb(60, 50, 20))
+            } else {
+                RichText::new(format!("{} : {}", sender, msg.msg_text)).color(Color32::WHITE)
+            };
+            let response = ui.label(text.font(FontId::proportional(14.0)));
+            if can_react {
+                response.context_menu(|ui| {
+                    ui.horizontal(|ui| {
+                        for emoji in REACTION_PALETTE {
+                            if ui.button(emoji).clicked() {
+                                let reacted = msg
+                                    .reactions
+                                    .iter()
+                                    .any(|chip| chip.emoji == emoji && chip.reacted);
+                                events.reactions.write(ReactionEvent {
+                                    msg_id: msg.msg_id,
+                                    emoji: emoji.to_string(),
+                                    add: !reacted,
+                                });
+                            }
+                        }
+                    });
+                    if ui.button("Reply in thread").clicked() {
+                        action.open_thread = Some(msg.reply_to.unwrap_or(msg.msg_id));
+                    }
+                    if ui.button("View profile").clicked() {
+                        action.profile = Some(msg.sender);
+                    }
+                    if !can_modify {
+                        return;
+                    }
+                    ui.separator();
+                    if is_own && ui.button("Edit").clicked() {
+                        action.editing = Some(msg.msg_id);
+                        action.currently_typing = msg.msg_text.clone();
+                    }
+                    if ui.button("Delete").clicked() {
+                        events
+                            .msg_actions
+                            .write(MessageActionEvent::Delete(msg.msg_id));
+                    }
+                });
+            }
+        });
+        ui.with_layout(Layout::right_to_left(egui::Align::RIGHT), |ui| {
+            ui.label(
+                RichText::new(format!("{}", get_formatted_time(msg.timestamp)))
+                    .font(FontId::proportional(12.0))
+                    .color(Color32::GRAY),
+            );
+            if msg.edited && !msg.deleted {
+                ui.label(
+                    RichText::new("(edited)")
+                        .font(FontId::proportional(12.0))
+                        .color(Color32::GRAY),
+                );
+            }
+        });
+    });
+    if !msg.reactions.is_empty() {
+        ui.horizontal(|ui| {
+            for chip in &msg.reactions {
+                if ui
+                    .selectable_label(chip.reacted, format!("{} {}", chip.emoji, chip.count))
+                    .clicked()
+                {
+                    events.reactions.write(ReactionEvent {
+                        msg_id: msg.msg_id,
+                        emoji: chip.emoji.clone(),
+                        add: !chip.reacted,
+                    });
+                }
+            }
+        });
+    }
+}
+
+fn show_channel_list(
+    ui: &mut egui::Ui,
+    action: &mut UserAction,
+    channel_events: &mut EventWriter<ChannelEvent>,
+    channels: &ChannelsResource,
+    unread: &HashMap<u64, (usize, bool)>,
+    contacts: &ContactsResource,
+    can_create_channels: bool,
+) {
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        ui.label(RichText::new("Channels").strong());
+        for channel in &channels.joined {
+            ui.horizontal(|ui| {
+                let selected = action.active_chat == Some(ChatTarget::Channel(channel.id));
+                let label = match unread.get(&channel.id) {
+                    Some((count, more)) if *count > 0 || *more => format!(
+                        "#{} ({}{})",
+                        channel.name,
+                        count,
+                        if *more { "+" } else { "" }
+                    ),
+                    _ => format!("#{}", channel.name),
+                };
+                if ui
+                    .selectable_label(selected, label)
+                    .on_hover_text(channel.topic.as_str())
+                    .clicked()
+                {
+                    action.active_chat = Some(ChatTarget::Channel(channel.id));
+                }
+                if selected && ui.small_button("Leave").clicked() {
+                    channel_events.write(ChannelEvent::Leave(channel.id));
+                }
+            });
+        }
+        if !channels.joinable.is_empty() {
+            ui.separator();
+            ui.label(RichText::new("Browse").strong());
+            for channel in &channels.joinable {
+                ui.horizontal(|ui| {
+                    ui.label(format!("#{}", channel.name))
+                        .on_hover_text(channel.topic.as_str());
+                    if ui.small_button("Join").clicked() {
+                        channel_events.write(ChannelEvent::Join(channel.id));
+                    }
+                });
+            }
+        }
+        if !contacts.users.is_empty() {
+            ui.separator();
+            ui.label(RichText::new("Direct messages").strong());
+            for user in &contacts.users {
+                let target = ChatTarget::Direct(user.identity);
+                let name = user.name.as_deref().unwrap_or_default();
+                let label = if user.online {
+                    RichText::new(name)
+                } else {
+                    RichText::new(name).color(Color32::GRAY)
+                };
+                if ui
+                    .selectable_label(action.active_chat == Some(target), label)
+                    .clicked()
+                {
+                    action.active_chat = Some(target);
+                }
+            }
+        }
+        if !can_create_channels {
+            return;
+        }
+        ui.separator();
+        ui.text_edit_singleline(&mut action.new_channel_name);
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut action.new_channel_private, "Private");
+            if ui.button("Create").clicked() && !action.new_channel_name.is_empty() {
+                channel_events.write(ChannelEvent::Create {
+                    name: std::mem::take(&mut action.new_channel_name),
+                    private: action.new_channel_private,
+                });
+            }
+        });
+    });
+}
+
 fn get_formatted_time(time: Timestamp) -> String {
     time.to_rfc3339().unwrap_or_default()[11..19].to_string()
 }
+
+fn get_formatted_date(time: Timestamp) -> String {
+    time.to_rfc3339()
+        .ok()
+        .and_then(|date| date.get(..10).map(str::to_string))
+        .unwrap_or_else(|| "unknown date".to_string())
+}
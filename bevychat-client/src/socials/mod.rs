@@ -23,6 +23,8 @@ pub struct UserInfo {
 pub enum ChatState {
     #[default]
     LoggedOut,
+    /// Waiting for the user to finish signing in with Discord in their browser.
+    AwaitingOAuth,
     LoggedIn,
 }
 
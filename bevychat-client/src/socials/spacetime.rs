@@ -1,17 +1,42 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Sender, channel},
+    },
+    time::Duration,
+};
 
-use bevy::prelude::*;
+use bevy::{audio::Pitch, prelude::*};
 use bevy_http_client::{HttpClient, HttpRequest, HttpResponse, HttpResponseError};
-use bevy_spacetimedb::StdbPlugin;
-use spacetimedb_sdk::{Table, Timestamp};
+use bevy_spacetimedb::{
+    AddEventChannelAppExtensions, DeleteEvent, InsertEvent, ReadDeleteEvent, ReadInsertEvent,
+    ReadInsertUpdateEvent, ReadReducerEvent, ReducerResultEvent, RegisterReducerEvent, StdbPlugin,
+};
+use spacetimedb_sdk::{Identity, ReducerEvent, Status, Table, TimeDuration, Timestamp};
 
 use crate::{
     module_bindings::{
-        DbConnection, MessageTableAccess, RemoteTables, UserTableAccess, send_message, set_name,
+        AuditEntry, Channel, ChannelMember, ChannelVisibility, DbConnection, DirectMessage,
+        ExternalAccount, ExternalAccountTableAccess, Mention, Message, ModerationLogTableAccess,
+        MyChannelMembersTableAccess, MyChannelMessagesTableAccess, MyChannelReactionsTableAccess,
+        MyChannelTypingTableAccess, MyChannelsTableAccess, MyDirectMessagesTableAccess,
+        MyMentionsTableAccess, MyPresenceTableAccess, MyReadMarkersTableAccess, NameChange,
+        PresenceStatus, Reaction, ReadMarker, Reducer, RemoteModule, RemoteReducers, RemoteTables,
+        RoleTableAccess, Sanction, SanctionKind, SanctionTableAccess, TrustedService, User,
+        UserRoleTableAccess, UserTableAccess, add_reaction, ban_user, create_channel,
+        delete_message, edit_message, join_channel, leave_channel, mark_read, mute_user,
+        remove_reaction, request_account_link, send_direct_message, send_message, set_name,
+        set_status, set_typing, unban_user, unlink_external_account, unmute_user,
     },
     socials::{
         ChatState, SpacetimeDB,
-        chatui::{LoginEvent, SendMessageEvent},
+        chatui::{
+            ChannelEvent, LoadOlderMessagesEvent, LoginEvent, MarkReadEvent, MessageActionEvent,
+            ModerationEvent, ReactionEvent, SendMessageEvent, StatusEvent, TypingEvent,
+            UnlinkAccountEvent,
+        },
     },
 };
 
@@ -19,137 +44,1450 @@ pub struct SpaceTimePlugin;
 
 impl Plugin for SpaceTimePlugin {
     fn build(&self, app: &mut App) {
+        let view_events = ViewEvents::new(app);
         app.add_plugins(
             StdbPlugin::default()
                 .with_uri("https://game-server.izaforge.com")
                 .with_module_name("bevychat")
                 .with_run_fn(DbConnection::run_threaded)
                 .add_table(RemoteTables::user)
-                .add_table(RemoteTables::message),
+                .add_table(RemoteTables::name_history)
+                .add_table(RemoteTables::trusted_service)
+                .add_table(RemoteTables::external_account)
+                .add_reducer::<SendMessage>()
+                .add_reducer::<SendDirectMessage>()
+                .add_reducer::<SetName>()
+                .add_reducer::<SetStatus>(),
         )
+        .insert_resource(view_events)
         .insert_resource(ChatDataResource::default())
+        .insert_resource(UnreadCounts::default())
+        .insert_resource(MessagePages::default())
+        .insert_resource(ChatNotice::default())
+        .insert_resource(ChannelsResource::default())
+        .insert_resource(ContactsResource::default())
+        .insert_resource(UserCache::default())
+        .insert_resource(ReactionCache::default())
+        .insert_resource(MentionToast::default())
+        .insert_resource(DiscordLogin::default())
+        .add_systems(Startup, load_mention_sound)
         .add_systems(OnEnter(ChatState::LoggedIn), subscribe_to_messages)
         .add_systems(
             Update,
-            (populate_chat_data, handle_send_message_event).run_if(in_state(ChatState::LoggedIn)),
+            (
+                subscribe_to_recent_messages,
+                handle_load_older_event,
+                ingest_messages,
+                remove_deleted_messages,
+                refresh_unread_counts
+                    .after(ingest_messages)
+                    .after(remove_deleted_messages),
+                cache_services,
+                refresh_reactions,
+                track_mentions,
+                ingest_direct_messages,
+                populate_channels,
+                populate_contacts,
+            )
+                .run_if(in_state(ChatState::LoggedIn)),
         )
         .add_systems(
             Update,
-            login_event_handler.run_if(in_state(ChatState::LoggedOut)),
+            (
+                handle_send_message_event,
+                handle_channel_event,
+                handle_message_action_event,
+                handle_reaction_event,
+                handle_moderation_event,
+                handle_typing_event,
+                handle_status_event,
+                handle_mark_read_event,
+                handle_unlink_account_event,
+                report_rejected_messages,
+            )
+                .run_if(in_state(ChatState::LoggedIn)),
+        )
+        // Users and their linked accounts are also cached while waiting for a Discord login,
+        // whose subscription delivers the local user's rows before the chat opens.
+        .add_systems(
+            Update,
+            (
+                cache_users,
+                cache_renames,
+                cache_linked_accounts,
+                handle_set_name_result,
+                expire_notice,
+                expire_mention_toast,
+            ),
+        )
+        .add_systems(
+            Update,
+            login_event_handler
+                .run_if(in_state(ChatState::LoggedOut).or(in_state(ChatState::AwaitingOAuth))),
         )
         .add_systems(
             Update,
             (handle_response, handle_error).run_if(in_state(ChatState::LoggedOut)),
+        )
+        .add_systems(OnEnter(ChatState::AwaitingOAuth), subscribe_to_own_account)
+        .add_systems(
+            Update,
+            (await_discord_login, handle_discord_login_status)
+                .run_if(in_state(ChatState::AwaitingOAuth)),
         );
     }
 }
 
+/// Permission bits of the module's roles, see `has_permission`.
+pub const DELETE_ANY: u32 = 1 << 1;
+pub const MUTE: u32 = 1 << 2;
+pub const BAN: u32 = 1 << 3;
+pub const CREATE_CHANNELS: u32 = 1 << 7;
+const MEMBER_ROLE: &str = "member";
+/// Provider name of linked Discord accounts.
+const DISCORD: &str = "discord";
+/// Where disco-server, which signs users in with Discord, listens.
+const DISCO_SERVER: &str = "http://localhost:42069";
+
+#[derive(RegisterReducerEvent)]
+pub struct SendMessage {
+    pub event: ReducerEvent<Reducer>,
+    pub channel_id: u64,
+    pub text: String,
+    pub reply_to: Option<u64>,
+}
+
+#[derive(RegisterReducerEvent)]
+pub struct SendDirectMessage {
+    pub event: ReducerEvent<Reducer>,
+    pub recipient: Identity,
+    pub text: String,
+}
+
+#[derive(RegisterReducerEvent)]
+pub struct SetStatus {
+    pub event: ReducerEvent<Reducer>,
+    pub status: PresenceStatus,
+    pub text: Option<String>,
+}
+
+#[derive(RegisterReducerEvent)]
+pub struct SetName {
+    pub event: ReducerEvent<Reducer>,
+    pub name: String,
+}
+
+/// A short-lived message for the local user, such as why the server rejected their message.
+#[derive(Resource, Default)]
+pub struct ChatNotice {
+    pub text: Option<String>,
+    expires_at: f32,
+}
+
+impl ChatNotice {
+    const DURATION_SECS: f32 = 5.0;
+
+    fn show(&mut self, text: impl Into<String>, time: &Time) {
+        self.text = Some(text.into());
+        self.expires_at = time.elapsed_secs() + Self::DURATION_SECS;
+    }
+}
+
+/// A Discord login the user is finishing in their browser.
+#[derive(Resource, Default)]
+struct DiscordLogin {
+    /// The login's secret state, which disco-server knows it by.
+    state: String,
+    started_at: f32,
+    next_poll: f32,
+}
+
+impl DiscordLogin {
+    /// How long the user has to sign in before the login is given up on.
+    const TIMEOUT_SECS: f32 = 300.0;
+    /// How often disco-server is asked whether the login was declined or failed.
+    const POLL_SECS: f32 = 2.0;
+}
+
+/// Tells the local user they were mentioned, along with a short chime.
+#[derive(Resource, Default)]
+pub struct MentionToast {
+    pub text: Option<String>,
+    expires_at: f32,
+}
+
+impl MentionToast {
+    const DURATION_SECS: f32 = 5.0;
+}
+
+#[derive(Resource)]
+struct MentionSound(Handle<Pitch>);
+
+/// A conversation the local user can read and post to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChatTarget {
+    Channel(u64),
+    /// A private conversation with the given identity.
+    Direct(Identity),
+}
+
+/// Forwards rows of the views that serve direct messages and channel content as Bevy events,
+/// since `StdbPlugin` can only register tables with a primary key.
+#[derive(Resource)]
+struct ViewEvents {
+    channels: Sender<InsertEvent<Channel>>,
+    deleted_channels: Sender<DeleteEvent<Channel>>,
+    members: Sender<InsertEvent<ChannelMember>>,
+    deleted_members: Sender<DeleteEvent<ChannelMember>>,
+    direct_messages: Sender<InsertEvent<DirectMessage>>,
+    messages: Sender<InsertEvent<Message>>,
+    deleted_messages: Sender<DeleteEvent<Message>>,
+    reactions: Sender<InsertEvent<Reaction>>,
+    deleted_reactions: Sender<DeleteEvent<Reaction>>,
+    mentions: Sender<InsertEvent<Mention>>,
+    deleted_mentions: Sender<DeleteEvent<Mention>>,
+    read_markers: Sender<InsertEvent<ReadMarker>>,
+}
+
+impl ViewEvents {
+    fn new(app: &mut App) -> Self {
+        Self {
+            channels: event_channel(app),
+            deleted_channels: event_channel(app),
+            members: event_channel(app),
+            deleted_members: event_channel(app),
+            direct_messages: event_channel(app),
+            messages: event_channel(app),
+            deleted_messages: event_channel(app),
+            reactions: event_channel(app),
+            deleted_reactions: event_channel(app),
+            mentions: event_channel(app),
+            deleted_mentions: event_channel(app),
+            read_markers: event_channel(app),
+        }
+    }
+}
+
+/// Registers a Bevy event channel and returns the end that rows are sent through.
+fn event_channel<T: Event>(app: &mut App) -> Sender<T> {
+    let (sender, receiver) = channel();
+    app.add_event_channel(receiver);
+    sender
+}
+
+fn forward_inserts<T: Clone + Send + 'static>(
+    table: impl Table<Row = T>,
+    sender: &Sender<InsertEvent<T>>,
+) {
+    let sender = sender.clone();
+    table.on_insert(move |_, row| {
+        let _ = sender.send(InsertEvent { row: row.clone() });
+    });
+}
+
+fn forward_deletes<T: Clone + Send + 'static>(
+    table: impl Table<Row = T>,
+    sender: &Sender<DeleteEvent<T>>,
+) {
+    let sender = sender.clone();
+    table.on_delete(move |_, row| {
+        let _ = sender.send(DeleteEvent { row: row.clone() });
+    });
+}
+
 #[derive(Resource, Default)]
 pub struct ChatDataResource {
-    pub msgs: VecDeque<ChatData>,
-    last_processed_id: u64,
+    /// The loaded messages of every conversation, oldest first.
+    pub msgs: HashMap<ChatTarget, VecDeque<ChatData>>,
+    /// Channel messages that mention the local user.
+    pub mentions: HashSet<u64>,
+    /// How many loaded replies each thread has, by the id of its first message.
+    replies: HashMap<u64, usize>,
+}
+
+impl ChatDataResource {
+    /// Adds a channel message in order, since rows don't arrive in order
+    /// and older pages arrive after newer messages.
+    fn insert(&mut self, target: ChatTarget, msg_data: ChatData) {
+        let msgs = self.msgs.entry(target).or_default();
+        let index = msgs.partition_point(|msg| msg.msg_id < msg_data.msg_id);
+        if msgs
+            .get(index)
+            .is_some_and(|msg| msg.msg_id == msg_data.msg_id)
+        {
+            return;
+        }
+        if let Some(parent_id) = msg_data.reply_to {
+            *self.replies.entry(parent_id).or_default() += 1;
+        }
+        msgs.insert(index, msg_data);
+    }
+
+    fn remove(&mut self, target: ChatTarget, msg_id: u64) {
+        let Some(msgs) = self.msgs.get_mut(&target) else {
+            return;
+        };
+        let Some(index) = msgs.iter().position(|msg| msg.msg_id == msg_id) else {
+            return;
+        };
+        let Some(parent_id) = msgs.remove(index).and_then(|msg| msg.reply_to) else {
+            return;
+        };
+        if let Some(count) = self.replies.get_mut(&parent_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.replies.remove(&parent_id);
+            }
+        }
+    }
+
+    /// How many replies to the message with `msg_id` are loaded.
+    pub fn reply_count(&self, msg_id: u64) -> usize {
+        self.replies.get(&msg_id).copied().unwrap_or_default()
+    }
+
+    /// Adds a direct message in order, keeping only the latest 50 of each conversation.
+    fn push(&mut self, target: ChatTarget, msg_data: ChatData) {
+        self.insert(target, msg_data);
+        let msgs = self.msgs.entry(target).or_default();
+        if msgs.len() > 50 {
+            msgs.pop_front();
+        }
+    }
+
+    fn find_mut(&mut self, target: ChatTarget, msg_id: u64) -> Option<&mut ChatData> {
+        self.msgs
+            .get_mut(&target)?
+            .iter_mut()
+            .find(|msg| msg.msg_id == msg_id)
+    }
+}
+
+/// How many loaded messages of each joined channel the local user hasn't read,
+/// and whether there are likely more among the pages that aren't loaded.
+#[derive(Resource, Default)]
+pub struct UnreadCounts(pub HashMap<u64, (usize, bool)>);
+
+/// Which part of each channel's history the client is subscribed to.
+/// Every channel starts with its latest page, and older pages are added on request.
+#[derive(Resource, Default)]
+pub struct MessagePages {
+    /// Sequence number after which every message of the channel is subscribed to.
+    starts: HashMap<u64, u64>,
+    /// Set once the subscription for the most recently requested page has been applied.
+    loaded: HashMap<u64, Arc<AtomicBool>>,
+}
+
+impl MessagePages {
+    /// How many messages each subscription covers.
+    const PAGE_SIZE: u64 = 50;
+
+    /// Whether the channel has messages older than the ones subscribed to.
+    pub fn has_older(&self, channel_id: u64) -> bool {
+        self.starts.get(&channel_id).is_some_and(|start| *start > 0)
+    }
+
+    /// Subscribes to the messages of a page and the reactions to them.
+    fn subscribe(&mut self, stdb: &SpacetimeDB, channel_id: u64, after: u64, up_to: Option<u64>) {
+        let mut filter = format!("channel_id = {} AND seq > {}", channel_id, after);
+        if let Some(up_to) = up_to {
+            filter.push_str(&format!(" AND seq <= {}", up_to));
+        }
+        let queries = [
+            format!("SELECT * FROM my_channel_messages WHERE {}", filter),
+            format!("SELECT * FROM my_channel_reactions WHERE {}", filter),
+        ];
+        let loaded = Arc::new(AtomicBool::new(false));
+        let on_applied = loaded.clone();
+        stdb.subscription_builder()
+            .on_applied(move |_| on_applied.store(true, Ordering::Relaxed))
+            .on_error(|_, err| error!("Subscription to messages failed for: {}", err))
+            .subscribe(queries);
+        self.starts.insert(channel_id, after);
+        self.loaded.insert(channel_id, loaded);
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ChannelsResource {
+    /// Channels the local user is a member of, sorted by name.
+    pub joined: Vec<Channel>,
+    /// Public channels the local user can still join, sorted by name.
+    pub joinable: Vec<Channel>,
+}
+
+#[derive(Resource, Default)]
+pub struct ContactsResource {
+    /// Every named user other than the local one, sorted by name.
+    pub users: Vec<User>,
+}
+
+/// Every user the client knows about, kept current from `user` row events
+/// so messages can show their sender's name at the time they are rendered.
+#[derive(Resource, Default)]
+pub struct UserCache {
+    users: HashMap<Identity, Option<String>>,
+    /// Every rename of each user, oldest first.
+    renames: HashMap<Identity, Vec<NameChange>>,
+    /// Names of the trusted services, which post system messages.
+    services: HashMap<Identity, String>,
+    /// Users who linked their Discord account.
+    verified: HashSet<Identity>,
+}
+
+impl UserCache {
+    /// The name `identity` goes by, or a guest name for users without one.
+    pub fn name(&self, identity: Identity) -> String {
+        self.users
+            .get(&identity)
+            .cloned()
+            .flatten()
+            .or_else(|| self.services.get(&identity).cloned())
+            .unwrap_or_else(|| guest_name(identity))
+    }
+
+    /// Whether `identity` hasn't set a name, including users the client doesn't know yet.
+    pub fn is_guest(&self, identity: Identity) -> bool {
+        !self.is_service(identity) && self.users.get(&identity).is_none_or(|name| name.is_none())
+    }
+
+    /// Every user the client knows about and the name they go by, guests included, sorted by name.
+    pub fn all(&self) -> Vec<(Identity, String)> {
+        let mut users: Vec<(Identity, String)> = self
+            .users
+            .keys()
+            .map(|identity| (*identity, self.name(*identity)))
+            .collect();
+        users.sort_by(|a, b| a.1.cmp(&b.1));
+        users
+    }
+
+    pub fn is_service(&self, identity: Identity) -> bool {
+        self.services.contains_key(&identity)
+    }
+
+    pub fn is_verified(&self, identity: Identity) -> bool {
+        self.verified.contains(&identity)
+    }
+
+    /// The name `identity` went by when sending something at `sent`,
+    /// if they have since renamed themselves.
+    pub fn renamed_from(&self, identity: Identity, sent: Timestamp) -> Option<&str> {
+        let current = self.users.get(&identity)?.as_deref()?;
+        let name = self
+            .renames
+            .get(&identity)?
+            .iter()
+            .rev()
+            .find(|change| change.changed_at <= sent)?
+            .new_name
+            .as_str();
+        (name != current).then_some(name)
+    }
+
+    fn update(&mut self, usr: &User) {
+        self.users.insert(usr.identity, usr.name.clone());
+    }
+
+    fn record_rename(&mut self, change: NameChange) {
+        let renames = self.renames.entry(change.identity).or_default();
+        let index = renames.partition_point(|other| other.changed_at <= change.changed_at);
+        renames.insert(index, change);
+    }
+}
+
+/// A stable name for a user without one, such as `guest-3f9a`.
+/// Identities share a common prefix, so the name is taken from the end.
+fn guest_name(identity: Identity) -> String {
+    let hex = identity.to_hex().to_string();
+    format!("guest-{}", &hex[hex.len() - 4..])
 }
 
 #[derive(Clone, Debug)]
 pub struct ChatData {
     pub msg_id: u64,
     pub msg_text: String,
-    // pub sender: Identity,
-    pub sender_username: String,
+    /// Resolved to a name when rendered, see `UserCache`.
+    pub sender: Identity,
     pub timestamp: Timestamp,
+    pub edited: bool,
+    /// Deleted messages stay in place so they can be rendered as tombstones.
+    pub deleted: bool,
+    pub reactions: Vec<ReactionChip>,
+    pub reply_to: Option<u64>,
+}
+
+/// All reactions to a message that use the same emoji.
+#[derive(Clone, Debug)]
+pub struct ReactionChip {
+    pub emoji: String,
+    pub count: usize,
+    /// Whether the local user is one of the reactors.
+    pub reacted: bool,
+}
+
+/// Reactions to the loaded messages, by message id, in the order they were added.
+#[derive(Resource, Default)]
+struct ReactionCache(HashMap<u64, Vec<Reaction>>);
+
+impl ReactionCache {
+    fn insert(&mut self, reaction: Reaction) {
+        let reactions = self.0.entry(reaction.message_id).or_default();
+        let index = reactions.partition_point(|other| other.id < reaction.id);
+        if reactions
+            .get(index)
+            .is_none_or(|other| other.id != reaction.id)
+        {
+            reactions.insert(index, reaction);
+        }
+    }
+
+    fn remove(&mut self, reaction: &Reaction) {
+        if let Some(reactions) = self.0.get_mut(&reaction.message_id) {
+            reactions.retain(|other| other.id != reaction.id);
+            if reactions.is_empty() {
+                self.0.remove(&reaction.message_id);
+            }
+        }
+    }
+
+    /// Groups the reactions to a message by emoji, in the order they were first used.
+    fn chips(&self, msg_id: u64, identity: Option<Identity>) -> Vec<ReactionChip> {
+        let mut chips: Vec<ReactionChip> = Vec::new();
+        for reaction in self.0.get(&msg_id).into_iter().flatten() {
+            let reacted = Some(reaction.reactor) == identity;
+            match chips.iter_mut().find(|chip| chip.emoji == reaction.emoji) {
+                Some(chip) => {
+                    chip.count += 1;
+                    chip.reacted |= reacted;
+                }
+                None => chips.push(ReactionChip {
+                    emoji: reaction.emoji.clone(),
+                    count: 1,
+                    reacted,
+                }),
+            }
+        }
+        chips
+    }
 }
 
 impl ChatData {
-    pub fn new(msg: crate::module_bindings::Message, usr: crate::module_bindings::User) -> Self {
+    pub fn new(msg: Message) -> Self {
         Self {
             msg_id: msg.id,
             msg_text: msg.text,
-            // sender: usr.identity,
-            sender_username: usr.name.unwrap(),
+            sender: msg.sender,
             timestamp: msg.sent,
+            edited: msg.edited_at.is_some(),
+            deleted: msg.deleted,
+            reactions: Vec::new(),
+            reply_to: msg.reply_to,
+        }
+    }
+
+    pub fn from_direct(dm: DirectMessage) -> Self {
+        Self {
+            msg_id: dm.id,
+            msg_text: dm.text,
+            sender: dm.sender,
+            timestamp: dm.sent,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: None,
         }
     }
 }
 
-fn subscribe_to_messages(stdb: SpacetimeDB) {
-    stdb.subscription_builder()
-        .on_error(|_, err| error!("Subscription to messages failed for: {}", err))
-        .subscribe("SELECT * FROM message");
+fn subscribe_to_messages(events: Res<ViewEvents>, stdb: SpacetimeDB) {
+    forward_inserts(stdb.db().my_direct_messages(), &events.direct_messages);
+    forward_inserts(stdb.db().my_channel_messages(), &events.messages);
+    // Views have no primary key, so an edited message is deleted and inserted again.
+    // Only forward deletes of messages that are gone for good.
+    let deleted_messages = events.deleted_messages.clone();
+    stdb.db().my_channel_messages().on_delete(move |ctx, msg| {
+        let replaced = ctx
+            .db
+            .my_channel_messages()
+            .iter()
+            .any(|current| current.id == msg.id);
+        if !replaced {
+            let _ = deleted_messages.send(DeleteEvent { row: msg.clone() });
+        }
+    });
+    forward_inserts(stdb.db().my_channels(), &events.channels);
+    forward_deletes(stdb.db().my_channels(), &events.deleted_channels);
+    forward_inserts(stdb.db().my_channel_members(), &events.members);
+    forward_deletes(stdb.db().my_channel_members(), &events.deleted_members);
+    forward_inserts(stdb.db().my_channel_reactions(), &events.reactions);
+    forward_deletes(stdb.db().my_channel_reactions(), &events.deleted_reactions);
+    forward_inserts(stdb.db().my_mentions(), &events.mentions);
+    forward_deletes(stdb.db().my_mentions(), &events.deleted_mentions);
+    forward_inserts(stdb.db().my_read_markers(), &events.read_markers);
+    // Channel messages and their reactions are subscribed to a page at a time, see `MessagePages`.
     stdb.subscription_builder()
         .on_error(|_, err| error!("Subscription to users failed for: {}", err))
-        .subscribe("SELECT * FROM user");
+        .subscribe(["SELECT * FROM user", "SELECT * FROM name_history"]);
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to channels failed for: {}", err))
+        .subscribe([
+            "SELECT * FROM my_channels",
+            "SELECT * FROM my_channel_members",
+        ]);
+    // Direct messages are only readable through a view scoped to the local identity.
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to direct messages failed for: {}", err))
+        .subscribe("SELECT * FROM my_direct_messages");
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to roles failed for: {}", err))
+        .subscribe(["SELECT * FROM role", "SELECT * FROM user_role"]);
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to services failed for: {}", err))
+        .subscribe("SELECT * FROM trusted_service");
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to linked accounts failed for: {}", err))
+        .subscribe("SELECT * FROM external_account");
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to sanctions failed for: {}", err))
+        .subscribe("SELECT * FROM sanction");
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to typing indicators failed for: {}", err))
+        .subscribe("SELECT * FROM my_channel_typing");
+    // Only the local user's own presence is readable, so invisible users stay hidden.
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to presence failed for: {}", err))
+        .subscribe("SELECT * FROM my_presence");
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to read markers failed for: {}", err))
+        .subscribe("SELECT * FROM my_read_markers");
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to mentions failed for: {}", err))
+        .subscribe("SELECT * FROM my_mentions");
+    // Only moderators get any rows from this view.
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to moderation log failed for: {}", err))
+        .subscribe("SELECT * FROM moderation_log");
+}
+
+/// Subscribes to the latest page of every joined channel that has no subscription yet.
+fn subscribe_to_recent_messages(
+    mut pages: ResMut<MessagePages>,
+    channels: Res<ChannelsResource>,
+    stdb: SpacetimeDB,
+) {
+    for channel in &channels.joined {
+        if !pages.starts.contains_key(&channel.id) {
+            let after = channel.last_seq.saturating_sub(MessagePages::PAGE_SIZE);
+            pages.subscribe(&stdb, channel.id, after, None);
+        }
+    }
+}
+
+fn handle_load_older_event(
+    mut events: EventReader<LoadOlderMessagesEvent>,
+    mut pages: ResMut<MessagePages>,
+    stdb: SpacetimeDB,
+) {
+    for LoadOlderMessagesEvent(channel_id) in events.read() {
+        let Some(&start) = pages.starts.get(channel_id) else {
+            continue;
+        };
+        let loading = pages
+            .loaded
+            .get(channel_id)
+            .is_some_and(|loaded| !loaded.load(Ordering::Relaxed));
+        if start == 0 || loading {
+            continue;
+        }
+        let after = start.saturating_sub(MessagePages::PAGE_SIZE);
+        pages.subscribe(&stdb, *channel_id, after, Some(start));
+    }
+}
+
+/// Adds new messages, and applies edits and deletions to the ones already loaded,
+/// which arrive as inserts too.
+fn ingest_messages(
+    mut events: ReadInsertEvent<Message>,
+    mut data: ResMut<ChatDataResource>,
+    reactions: Res<ReactionCache>,
+    stdb: SpacetimeDB,
+) {
+    let identity = stdb.try_identity();
+    for event in events.read() {
+        let msg = event.row.clone();
+        let target = ChatTarget::Channel(msg.channel_id);
+        if let Some(msg_data) = data.find_mut(target, msg.id) {
+            msg_data.msg_text = msg.text;
+            msg_data.edited = msg.edited_at.is_some();
+            msg_data.deleted = msg.deleted;
+            continue;
+        }
+        let mut msg_data = ChatData::new(msg);
+        msg_data.reactions = reactions.chips(msg_data.msg_id, identity);
+        data.insert(target, msg_data);
+    }
+}
+
+fn remove_deleted_messages(
+    mut events: ReadDeleteEvent<Message>,
+    mut data: ResMut<ChatDataResource>,
+) {
+    for event in events.read() {
+        data.remove(ChatTarget::Channel(event.row.channel_id), event.row.id);
+    }
+}
+
+fn cache_users(
+    mut events: ReadInsertUpdateEvent<User>,
+    mut deleted: ReadDeleteEvent<User>,
+    mut users: ResMut<UserCache>,
+) {
+    for event in events.read() {
+        users.update(&event.new);
+    }
+    for event in deleted.read() {
+        users.users.remove(&event.row.identity);
+    }
+}
+
+fn cache_renames(mut events: ReadInsertEvent<NameChange>, mut users: ResMut<UserCache>) {
+    for event in events.read() {
+        users.record_rename(event.row.clone());
+    }
 }
 
-fn populate_chat_data(mut data: ResMut<ChatDataResource>, stdb: SpacetimeDB) {
-    let mut msgs: Vec<_> = stdb
+fn cache_services(
+    mut events: ReadInsertUpdateEvent<TrustedService>,
+    mut deleted: ReadDeleteEvent<TrustedService>,
+    mut users: ResMut<UserCache>,
+) {
+    for event in events.read() {
+        users
+            .services
+            .insert(event.new.identity, event.new.name.clone());
+    }
+    for event in deleted.read() {
+        users.services.remove(&event.row.identity);
+    }
+}
+
+fn cache_linked_accounts(
+    mut events: ReadInsertEvent<ExternalAccount>,
+    mut deleted: ReadDeleteEvent<ExternalAccount>,
+    mut users: ResMut<UserCache>,
+) {
+    // Users have at most one account per provider, so unlinking it unverifies them.
+    for event in events.read() {
+        if event.row.provider == DISCORD {
+            users.verified.insert(event.row.identity);
+        }
+    }
+    for event in deleted.read() {
+        if event.row.provider == DISCORD {
+            users.verified.remove(&event.row.identity);
+        }
+    }
+}
+
+fn load_mention_sound(mut commands: Commands, mut pitches: ResMut<Assets<Pitch>>) {
+    let chime = Pitch::new(880.0, Duration::from_millis(150));
+    commands.insert_resource(MentionSound(pitches.add(chime)));
+}
+
+/// Keeps track of which messages mention the local user, and notifies them
+/// of new mentions unless they don't want to be disturbed.
+fn track_mentions(
+    mut inserted: ReadInsertEvent<Mention>,
+    mut deleted: ReadDeleteEvent<Mention>,
+    mut data: ResMut<ChatDataResource>,
+    mut toast: ResMut<MentionToast>,
+    mut commands: Commands,
+    sound: Res<MentionSound>,
+    users: Res<UserCache>,
+    time: Res<Time>,
+    stdb: SpacetimeDB,
+) {
+    // Mentions from before the subscription was made only mark their messages.
+    const RECENT_MICROS: i64 = 10_000_000;
+    let now = Timestamp::now();
+    let mut notify = None;
+    for event in inserted.read() {
+        let mention = &event.row;
+        data.mentions.insert(mention.message_id);
+        let recent = now
+            .time_duration_since(mention.at)
+            .is_none_or(|age| age.to_micros() < RECENT_MICROS);
+        if recent {
+            notify = Some(mention.clone());
+        }
+    }
+    for event in deleted.read() {
+        data.mentions.remove(&event.row.message_id);
+    }
+    let Some(mention) = notify else {
+        return;
+    };
+    if own_status(&stdb) == PresenceStatus::DoNotDisturb {
+        return;
+    }
+    let channel = stdb
+        .db()
+        .my_channels()
+        .iter()
+        .find(|channel| channel.id == mention.channel_id)
+        .map_or_else(String::new, |channel| format!(" in #{}", channel.name));
+    toast.text = Some(format!(
+        "{} mentioned you{}",
+        users.name(mention.sender),
+        channel
+    ));
+    toast.expires_at = time.elapsed_secs() + MentionToast::DURATION_SECS;
+    commands.spawn((AudioPlayer(sound.0.clone()), PlaybackSettings::DESPAWN));
+}
+
+/// Keeps the reaction cache current, and the reactions shown on messages that are loaded.
+/// Reactions arrive with the page of messages they belong to, in either order.
+fn refresh_reactions(
+    mut inserted: ReadInsertEvent<Reaction>,
+    mut deleted: ReadDeleteEvent<Reaction>,
+    mut data: ResMut<ChatDataResource>,
+    mut reactions: ResMut<ReactionCache>,
+    stdb: SpacetimeDB,
+) {
+    let mut changed: Vec<(u64, u64)> = Vec::new();
+    for event in inserted.read() {
+        changed.push((event.row.channel_id, event.row.message_id));
+        reactions.insert(event.row.clone());
+    }
+    for event in deleted.read() {
+        changed.push((event.row.channel_id, event.row.message_id));
+        reactions.remove(&event.row);
+    }
+    changed.sort_unstable();
+    changed.dedup();
+    let identity = stdb.try_identity();
+    for (channel_id, msg_id) in changed {
+        if let Some(msg_data) = data.find_mut(ChatTarget::Channel(channel_id), msg_id) {
+            msg_data.reactions = reactions.chips(msg_id, identity);
+        }
+    }
+}
+
+/// Whether `identity` has `permission` through its roles or the member role,
+/// mirroring the module's permission check.
+pub fn has_permission(stdb: &SpacetimeDB, identity: Identity, permission: u32) -> bool {
+    let member = stdb
+        .db()
+        .role()
+        .iter()
+        .find(|role| role.name == MEMBER_ROLE)
+        .map_or(0, |role| role.permissions);
+    let permissions = stdb
         .db()
-        .message()
+        .user_role()
         .iter()
-        .filter(|msg| msg.id > data.last_processed_id)
+        .filter(|user_role| user_role.identity == identity)
+        .filter_map(|user_role| stdb.db().role().id().find(&user_role.role_id))
+        .fold(member, |permissions, role| permissions | role.permissions);
+    permissions & permission == permission
+}
+
+/// The most recent entries of the moderation log, newest first.
+pub fn recent_audit_entries(stdb: &SpacetimeDB, count: usize) -> Vec<AuditEntry> {
+    let mut entries: Vec<_> = stdb.db().moderation_log().iter().collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.id));
+    entries.truncate(count);
+    entries
+}
+
+/// Everyone but the local user who is typing in a channel, in the order they started.
+pub fn typing_users(stdb: &SpacetimeDB, channel_id: u64) -> Vec<Identity> {
+    let identity = stdb.try_identity();
+    let now = Timestamp::now();
+    let mut typing: Vec<_> = stdb
+        .db()
+        .my_channel_typing()
+        .iter()
+        .filter(|typing| typing.channel_id == channel_id && Some(typing.identity) != identity)
+        .filter(|typing| typing.expires_at > now)
         .collect();
-    if !msgs.is_empty() {
-        msgs.sort_by_key(|msg| msg.id);
-        for msg in msgs {
-            if let Some(usr) = stdb
-                .db()
-                .user()
-                .iter()
-                .find(|user| user.identity == msg.sender)
-            {
-                let msg_data = ChatData::new(msg, usr);
-                data.last_processed_id = msg_data.msg_id;
-                data.msgs.push_back(msg_data);
-                if data.msgs.len() > 50 {
-                    data.msgs.pop_front();
-                }
+    typing.sort_by_key(|typing| typing.id);
+    typing.into_iter().map(|typing| typing.identity).collect()
+}
+
+/// The id of the last message the local user has read in a channel, or 0 if they read none.
+pub fn last_read(stdb: &SpacetimeDB, channel_id: u64) -> u64 {
+    stdb.db()
+        .my_read_markers()
+        .iter()
+        .find(|marker| marker.channel_id == channel_id)
+        .map_or(0, |marker| marker.last_read_message_id)
+}
+
+/// Recounts unread messages once messages, read markers, pages or channels change,
+/// since counting goes through every loaded message.
+fn refresh_unread_counts(
+    mut inserted: ReadInsertEvent<Message>,
+    mut deleted: ReadDeleteEvent<Message>,
+    mut markers: ReadInsertEvent<ReadMarker>,
+    data: Res<ChatDataResource>,
+    pages: Res<MessagePages>,
+    channels: Res<ChannelsResource>,
+    mut unread: ResMut<UnreadCounts>,
+    stdb: SpacetimeDB,
+) {
+    // Count every reader so none of them are left with events for the next frame.
+    let changes = inserted.read().count() + deleted.read().count() + markers.read().count();
+    if changes == 0 && !pages.is_changed() && !channels.is_changed() {
+        return;
+    }
+    unread.0 = channels
+        .joined
+        .iter()
+        .map(|channel| {
+            let count = unread_count(&stdb, &data, &pages, channel.id);
+            (channel.id, count)
+        })
+        .collect();
+}
+
+fn unread_count(
+    stdb: &SpacetimeDB,
+    data: &ChatDataResource,
+    pages: &MessagePages,
+    channel_id: u64,
+) -> (usize, bool) {
+    let Some(msgs) = data.msgs.get(&ChatTarget::Channel(channel_id)) else {
+        return (0, false);
+    };
+    let last_read = last_read(stdb, channel_id);
+    let identity = stdb.try_identity();
+    let count = msgs
+        .iter()
+        .filter(|msg| msg.msg_id > last_read && Some(msg.sender) != identity && !msg.deleted)
+        .count();
+    let more =
+        pages.has_older(channel_id) && msgs.front().is_some_and(|msg| msg.msg_id > last_read);
+    (count, more)
+}
+
+/// Accounts `identity` has linked with other providers.
+pub fn linked_accounts(stdb: &SpacetimeDB, identity: Identity) -> Vec<ExternalAccount> {
+    let mut accounts: Vec<ExternalAccount> = stdb
+        .db()
+        .external_account()
+        .iter()
+        .filter(|account| account.identity == identity)
+        .collect();
+    accounts.sort_by(|a, b| a.provider.cmp(&b.provider));
+    accounts
+}
+
+/// The status the local user picked, which others see unless it's invisible.
+pub fn own_status(stdb: &SpacetimeDB) -> PresenceStatus {
+    stdb.db()
+        .my_presence()
+        .iter()
+        .next()
+        .map_or(PresenceStatus::Online, |presence| presence.status)
+}
+
+/// The status text the local user set, if any.
+pub fn own_status_text(stdb: &SpacetimeDB) -> Option<String> {
+    let identity = stdb.try_identity()?;
+    stdb.db().user().identity().find(&identity)?.status_text
+}
+
+/// The users taking part in a conversation, online ones first and then by name.
+pub fn conversation_members(stdb: &SpacetimeDB, target: ChatTarget) -> Vec<User> {
+    let identities: Vec<Identity> = match target {
+        ChatTarget::Channel(channel_id) => stdb
+            .db()
+            .my_channel_members()
+            .iter()
+            .filter(|member| member.channel_id == channel_id)
+            .map(|member| member.member)
+            .collect(),
+        ChatTarget::Direct(peer) => stdb.try_identity().into_iter().chain([peer]).collect(),
+    };
+    let mut members: Vec<User> = identities
+        .into_iter()
+        .filter_map(|identity| stdb.db().user().identity().find(&identity))
+        .collect();
+    members.sort_by(|a, b| b.online.cmp(&a.online).then_with(|| a.name.cmp(&b.name)));
+    members.dedup_by_key(|user| user.identity);
+    members
+}
+
+/// Sanctions of `kind` on `identity` that are still in effect.
+pub fn active_sanctions(
+    stdb: &SpacetimeDB,
+    identity: Identity,
+    kind: SanctionKind,
+) -> Vec<Sanction> {
+    let now = Timestamp::now();
+    stdb.db()
+        .sanction()
+        .iter()
+        .filter(|sanction| sanction.target == identity && sanction.kind == kind)
+        .filter(|sanction| sanction.until.is_none_or(|until| until > now))
+        .collect()
+}
+
+fn ingest_direct_messages(
+    mut events: ReadInsertEvent<DirectMessage>,
+    mut data: ResMut<ChatDataResource>,
+    stdb: SpacetimeDB,
+) {
+    let Some(identity) = stdb.try_identity() else {
+        return;
+    };
+    for event in events.read() {
+        let dm = event.row.clone();
+        // Direct messages are filed under the other party of the conversation.
+        let peer = if dm.sender == identity {
+            dm.recipient
+        } else {
+            dm.sender
+        };
+        data.push(ChatTarget::Direct(peer), ChatData::from_direct(dm));
+    }
+}
+
+/// Rebuilds the channel lists whenever a channel or membership changes.
+fn populate_channels(
+    mut channel_events: ReadInsertEvent<Channel>,
+    mut deleted_channels: ReadDeleteEvent<Channel>,
+    mut member_events: ReadInsertEvent<ChannelMember>,
+    mut deleted_members: ReadDeleteEvent<ChannelMember>,
+    mut channels: ResMut<ChannelsResource>,
+    stdb: SpacetimeDB,
+) {
+    // Count every reader so none of them are left with events for the next frame.
+    let changes = channel_events.read().count()
+        + deleted_channels.read().count()
+        + member_events.read().count()
+        + deleted_members.read().count();
+    if changes == 0 {
+        return;
+    }
+    let Some(identity) = stdb.try_identity() else {
+        return;
+    };
+    let joined_ids: Vec<u64> = stdb
+        .db()
+        .my_channel_members()
+        .iter()
+        .filter(|member| member.member == identity)
+        .map(|member| member.channel_id)
+        .collect();
+    let (mut joined, mut joinable): (Vec<_>, Vec<_>) = stdb
+        .db()
+        .my_channels()
+        .iter()
+        .filter(|channel| {
+            joined_ids.contains(&channel.id) || channel.visibility == ChannelVisibility::Public
+        })
+        .partition(|channel| joined_ids.contains(&channel.id));
+    joined.sort_by(|a, b| a.name.cmp(&b.name));
+    joinable.sort_by(|a, b| a.name.cmp(&b.name));
+    channels.joined = joined;
+    channels.joinable = joinable;
+}
+
+/// Rebuilds the contact list whenever a user joins, renames or goes on- or offline.
+fn populate_contacts(
+    mut user_events: ReadInsertUpdateEvent<User>,
+    mut deleted_users: ReadDeleteEvent<User>,
+    mut contacts: ResMut<ContactsResource>,
+    stdb: SpacetimeDB,
+) {
+    if user_events.read().count() + deleted_users.read().count() == 0 {
+        return;
+    }
+    let Some(identity) = stdb.try_identity() else {
+        return;
+    };
+    let mut users: Vec<_> = stdb
+        .db()
+        .user()
+        .iter()
+        .filter(|user| user.identity != identity && user.name.is_some())
+        .collect();
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    contacts.users = users;
+}
+
+fn handle_send_message_event(mut events: EventReader<SendMessageEvent>, stdb: SpacetimeDB) {
+    for event in events.read() {
+        match event.target {
+            ChatTarget::Channel(channel_id) => stdb
+                .reducers()
+                .send_message(channel_id, event.content.clone(), event.reply_to)
+                .unwrap(),
+            ChatTarget::Direct(recipient) => stdb
+                .reducers()
+                .send_direct_message(recipient, event.content.clone())
+                .unwrap(),
+        }
+    }
+}
+
+fn handle_channel_event(mut events: EventReader<ChannelEvent>, stdb: SpacetimeDB) {
+    for event in events.read() {
+        let result = match event {
+            ChannelEvent::Create { name, private } => {
+                let visibility = if *private {
+                    ChannelVisibility::Private
+                } else {
+                    ChannelVisibility::Public
+                };
+                stdb.reducers()
+                    .create_channel(name.clone(), String::new(), visibility)
             }
+            ChannelEvent::Join(channel_id) => stdb.reducers().join_channel(*channel_id),
+            ChannelEvent::Leave(channel_id) => stdb.reducers().leave_channel(*channel_id),
+        };
+        if let Err(err) = result {
+            error!("Channel request failed: {}", err);
         }
     }
 }
 
-fn handle_send_message_event(mut events: EventReader<SendMessageEvent>, stdb: SpacetimeDB) {
+fn handle_message_action_event(mut events: EventReader<MessageActionEvent>, stdb: SpacetimeDB) {
+    for event in events.read() {
+        let result = match event {
+            MessageActionEvent::Edit { msg_id, text } => {
+                stdb.reducers().edit_message(*msg_id, text.clone())
+            }
+            MessageActionEvent::Delete(msg_id) => stdb.reducers().delete_message(*msg_id),
+        };
+        if let Err(err) = result {
+            error!("Message request failed: {}", err);
+        }
+    }
+}
+
+fn handle_reaction_event(mut events: EventReader<ReactionEvent>, stdb: SpacetimeDB) {
+    for event in events.read() {
+        let result = if event.add {
+            stdb.reducers()
+                .add_reaction(event.msg_id, event.emoji.clone())
+        } else {
+            stdb.reducers()
+                .remove_reaction(event.msg_id, event.emoji.clone())
+        };
+        if let Err(err) = result {
+            error!("Reaction request failed: {}", err);
+        }
+    }
+}
+
+/// Tells the server the local user is typing at most once per `TYPING_REFRESH_SECS`,
+/// which keeps their indicator alive without a reducer call per keystroke.
+fn handle_typing_event(
+    mut events: EventReader<TypingEvent>,
+    mut last_sent: Local<Option<(u64, f32)>>,
+    time: Res<Time>,
+    stdb: SpacetimeDB,
+) {
+    const TYPING_REFRESH_SECS: f32 = 3.0;
+    let now = time.elapsed_secs();
+    for event in events.read() {
+        if event.typing {
+            let fresh = matches!(*last_sent, Some((channel_id, sent_at))
+                if channel_id == event.channel_id && now - sent_at < TYPING_REFRESH_SECS);
+            if fresh {
+                continue;
+            }
+            *last_sent = Some((event.channel_id, now));
+        } else if last_sent.take().is_none() {
+            // The server was never told the user is typing.
+            continue;
+        }
+        if let Err(err) = stdb.reducers().set_typing(event.channel_id, event.typing) {
+            error!("Typing request failed: {}", err);
+        }
+    }
+}
+
+fn handle_mark_read_event(
+    mut events: EventReader<MarkReadEvent>,
+    mut requested: Local<HashMap<u64, u64>>,
+    stdb: SpacetimeDB,
+) {
+    for event in events.read() {
+        // The marker only moves forward, and the view may not reflect the last request yet.
+        let marked = last_read(&stdb, event.channel_id)
+            .max(requested.get(&event.channel_id).copied().unwrap_or(0));
+        if event.message_id <= marked {
+            continue;
+        }
+        requested.insert(event.channel_id, event.message_id);
+        if let Err(err) = stdb
+            .reducers()
+            .mark_read(event.channel_id, event.message_id)
+        {
+            error!("Mark read request failed: {}", err);
+        }
+    }
+}
+
+fn handle_unlink_account_event(mut events: EventReader<UnlinkAccountEvent>, stdb: SpacetimeDB) {
+    for event in events.read() {
+        if let Err(err) = stdb
+            .reducers()
+            .unlink_external_account(event.provider.clone())
+        {
+            error!("Unlink request failed: {}", err);
+        }
+    }
+}
+
+fn handle_status_event(mut events: EventReader<StatusEvent>, stdb: SpacetimeDB) {
+    for event in events.read() {
+        if let Err(err) = stdb.reducers().set_status(event.status, event.text.clone()) {
+            error!("Status request failed: {}", err);
+        }
+    }
+}
+
+fn handle_moderation_event(mut events: EventReader<ModerationEvent>, stdb: SpacetimeDB) {
     for event in events.read() {
-        stdb.reducers().send_message(event.content.clone()).unwrap();
+        let result = match event {
+            ModerationEvent::Ban {
+                identity,
+                reason,
+                duration,
+            } => stdb.reducers().ban_user(
+                *identity,
+                reason.clone(),
+                duration.map(|duration| Timestamp::now() + duration),
+            ),
+            ModerationEvent::Mute { identity, duration } => {
+                stdb.reducers().mute_user(*identity, *duration)
+            }
+            ModerationEvent::Unban(identity) => stdb.reducers().unban_user(*identity),
+            ModerationEvent::Unmute(identity) => stdb.reducers().unmute_user(*identity),
+        };
+        if let Err(err) = result {
+            error!("Moderation request failed: {}", err);
+        }
+    }
+}
+
+fn report_rejected_messages(
+    mut sent: ReadReducerEvent<SendMessage>,
+    mut sent_direct: ReadReducerEvent<SendDirectMessage>,
+    mut status_changes: ReadReducerEvent<SetStatus>,
+    mut notice: ResMut<ChatNotice>,
+    time: Res<Time>,
+    stdb: SpacetimeDB,
+) {
+    let events = sent
+        .read()
+        .map(|sent| &sent.result.event)
+        .chain(sent_direct.read().map(|sent| &sent.result.event))
+        .chain(status_changes.read().map(|change| &change.result.event));
+    for event in events {
+        if Some(event.caller_identity) != stdb.try_identity() {
+            continue;
+        }
+        if let Status::Failed(reason) = &event.status {
+            notice.show(describe_rejection(reason), &time);
+        }
+    }
+}
+
+fn handle_set_name_result(
+    mut results: ReadReducerEvent<SetName>,
+    mut state: ResMut<NextState<ChatState>>,
+    mut notice: ResMut<ChatNotice>,
+    time: Res<Time>,
+    stdb: SpacetimeDB,
+) {
+    for result in results.read() {
+        let event = &result.result.event;
+        if Some(event.caller_identity) != stdb.try_identity() {
+            continue;
+        }
+        match &event.status {
+            Status::Committed => state.set(ChatState::LoggedIn),
+            Status::Failed(reason) => notice.show(describe_rejection(reason), &time),
+            Status::OutOfEnergy => notice.show("The server is out of energy", &time),
+        }
+    }
+}
+
+/// Strips the `[code]` prefix the server puts in front of validation failures.
+fn describe_rejection(reason: &str) -> &str {
+    reason
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+        .map_or(reason, |(_code, description)| description)
+}
+
+fn expire_mention_toast(mut toast: ResMut<MentionToast>, time: Res<Time>) {
+    if toast.text.is_some() && time.elapsed_secs() >= toast.expires_at {
+        toast.text = None;
+    }
+}
+
+fn expire_notice(mut notice: ResMut<ChatNotice>, time: Res<Time>) {
+    if notice.text.is_some() && time.elapsed_secs() >= notice.expires_at {
+        notice.text = None;
     }
 }
 
 fn login_event_handler(
     mut events: EventReader<LoginEvent>,
     stdb: SpacetimeDB,
-    mut state: ResMut<NextState<ChatState>>,
     mut ev_request: EventWriter<HttpRequest>,
+    login: Res<DiscordLogin>,
+    mut state: ResMut<NextState<ChatState>>,
 ) {
     for event in events.read() {
         match event {
+            // The chat opens once the server has accepted the name, see `handle_set_name_result`.
             LoginEvent::Username(usr) => {
                 stdb.reducers().set_name(usr.to_string()).unwrap();
-                state.set(ChatState::LoggedIn);
             }
+            // The module only links accounts to users who started signing in themselves.
             LoginEvent::Discord => {
-                let url = format!("http://localhost:42069/csrf/{}", stdb.identity());
-                info!("identity: {}", url);
+                if let Err(err) = stdb.reducers().request_account_link(DISCORD.to_string()) {
+                    error!("Requesting account link failed: {}", err);
+                    continue;
+                }
+                let url = format!("{}/csrf/{}", DISCO_SERVER, stdb.identity());
                 match HttpClient::new().get(url).try_build() {
                     Ok(request) => {
                         ev_request.write(request);
                     }
                     Err(e) => {
-                        eprintln!("Failed to build request: {}", e);
+                        error!("Failed to build request: {}", e);
+                    }
+                }
+            }
+            // disco-server drops the login too, so it can't be finished in the browser anymore.
+            LoginEvent::CancelDiscord => {
+                let url = format!("{}/cancel/{}", DISCO_SERVER, login.state);
+                match HttpClient::new().post(url).try_build() {
+                    Ok(request) => {
+                        ev_request.write(request);
+                    }
+                    Err(e) => {
+                        error!("Failed to build request: {}", e);
                     }
                 }
+                state.set(ChatState::LoggedOut);
             }
         }
     }
 }
 
-fn handle_response(mut ev_resp: EventReader<HttpResponse>) {
+fn handle_response(
+    mut ev_resp: EventReader<HttpResponse>,
+    mut login: ResMut<DiscordLogin>,
+    mut state: ResMut<NextState<ChatState>>,
+    time: Res<Time>,
+) {
     for response in ev_resp.read() {
-        info!("response {}", response.text().unwrap());
-        let authorize_url = format!(
-            "https://discord.com/oauth2/authorize?client_id=1415091415574118560&state={}&response_type=code&redirect_uri=http%3A%2F%2Flocalhost%3A42069%2F&scope=identify+guilds.members.read",
-            response.text().unwrap().to_string()
-        );
+        // Responses to cancelled logins arrive here too, once the login window is back.
+        if !response.url.contains("/csrf/") {
+            continue;
+        }
+        if !response.ok {
+            error!("Starting Discord login failed: {}", response.status_text);
+            continue;
+        }
+        // disco-server builds the authorization URL, as it holds the PKCE verifier for it.
+        let authorize_url = response.text().unwrap().to_string();
         println!("url: {:#?}", authorize_url);
+        let Some(login_state) = authorize_url
+            .split(['?', '&'])
+            .find_map(|param| param.strip_prefix("state="))
+        else {
+            error!("Discord login URL has no state: {}", authorize_url);
+            continue;
+        };
+        *login = DiscordLogin {
+            state: login_state.to_string(),
+            started_at: time.elapsed_secs(),
+            next_poll: time.elapsed_secs() + DiscordLogin::POLL_SECS,
+        };
         let _jh = open::that_in_background(authorize_url);
+        state.set(ChatState::AwaitingOAuth);
+    }
+}
+
+/// Subscribes to the local user's row and linked accounts, which show when disco-server
+/// has linked their Discord account.
+fn subscribe_to_own_account(stdb: SpacetimeDB) {
+    stdb.subscription_builder()
+        .on_error(|_, err| error!("Subscription to own account failed for: {}", err))
+        .subscribe([
+            "SELECT * FROM user WHERE identity = :sender",
+            "SELECT * FROM external_account WHERE identity = :sender",
+        ]);
+}
+
+/// Opens the chat once the local user has a linked Discord account, which is right away
+/// for users who linked it before, and gives up on logins that take too long.
+fn await_discord_login(
+    mut login: ResMut<DiscordLogin>,
+    mut state: ResMut<NextState<ChatState>>,
+    mut notice: ResMut<ChatNotice>,
+    mut ev_request: EventWriter<HttpRequest>,
+    time: Res<Time>,
+    stdb: SpacetimeDB,
+) {
+    let linked = stdb.try_identity().is_some_and(|identity| {
+        linked_accounts(&stdb, identity)
+            .iter()
+            .any(|account| account.provider == DISCORD)
+    });
+    if linked {
+        state.set(ChatState::LoggedIn);
+        return;
+    }
+    let now = time.elapsed_secs();
+    if now - login.started_at >= DiscordLogin::TIMEOUT_SECS {
+        notice.show("Signing in with Discord timed out", &time);
+        state.set(ChatState::LoggedOut);
+    } else if now >= login.next_poll {
+        login.next_poll = now + DiscordLogin::POLL_SECS;
+        let url = format!("{}/status/{}", DISCO_SERVER, login.state);
+        match HttpClient::new().get(url).try_build() {
+            Ok(request) => {
+                ev_request.write(request);
+            }
+            Err(e) => {
+                error!("Failed to build request: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns to the login window when disco-server reports that the Discord login
+/// was declined or failed. Successful logins show up through the subscription instead.
+fn handle_discord_login_status(
+    mut ev_resp: EventReader<HttpResponse>,
+    mut state: ResMut<NextState<ChatState>>,
+    mut notice: ResMut<ChatNotice>,
+    time: Res<Time>,
+) {
+    for response in ev_resp.read() {
+        let reason = match response.text().unwrap_or_default() {
+            // Logins are in progress while disco-server links the account.
+            "pending" | "in_progress" => continue,
+            "denied" => "Signing in with Discord was cancelled",
+            "failed" => "Signing in with Discord failed",
+            "unknown" => "The Discord login expired, please try again",
+            _ => continue,
+        };
+        notice.show(reason, &time);
+        state.set(ChatState::LoggedOut);
     }
 }
 
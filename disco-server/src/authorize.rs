@@ -1,41 +1,78 @@
 use axum::extract::{Query, State};
 use oauth2::{
-    AuthorizationCode, ClientId, ClientSecret, RedirectUrl, TokenResponse, TokenUrl,
-    basic::BasicClient,
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, EndpointNotSet, EndpointSet,
+    PkceCodeVerifier, RedirectUrl, TokenResponse, TokenUrl, basic::BasicClient,
 };
 use reqwest::StatusCode;
 use serde::Deserialize;
+use spacetimedb_sdk::{Identity, Status};
+use std::time::Duration;
+use tokio::{sync::oneshot, time};
 
 use crate::{
-    SharedCache, db,
-    module_bindings::set_name,
+    SharedCache,
+    csrf::LoginOutcome,
+    db,
+    module_bindings::link_external_account,
     secret::{APP_SECRET, APPLICATION_ID},
 };
 
+/// How long to wait for the module to link the account.
+const LINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OAuth2 client for Discord, able to build authorization URLs and exchange codes.
+pub(crate) type DiscordClient =
+    BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+
 #[derive(Deserialize)]
 pub struct AuthResponse {
-    code: String,
+    /// Missing when the user declined, in which case Discord sends an `error` instead.
+    code: Option<String>,
     state: String,
 }
 
 #[derive(Deserialize)]
 struct DiscordUser {
+    id: String,
     username: String,
+    /// Hash of the user's avatar, if they uploaded one.
+    avatar: Option<String>,
 }
 
 pub(crate) async fn disco_auth(
     State(state): State<SharedCache>,
     query: Query<AuthResponse>,
 ) -> Result<String, StatusCode> {
-    {
-        let mut cache = state.lock().await;
-        if cache.take_by_state(&query.state).is_none() {
-            return Err(StatusCode::UNAUTHORIZED);
-        }
-    }
-    // set oauth2 client
-    let client = BasicClient::new(ClientId::new(APPLICATION_ID.into()))
+    // The state was signed for the identity of the client that started the login,
+    // which is the user the Discord account gets linked to.
+    let (identity, verifier) = state.lock().await.redeem(&query.state).map_err(|err| {
+        eprintln!("Rejected Discord login: {}", err);
+        err.status_code()
+    })?;
+    let Some(code) = query.code.clone() else {
+        state
+            .lock()
+            .await
+            .finish(query.state.clone(), LoginOutcome::Denied);
+        return Ok("Login cancelled, you can close this tab".to_string());
+    };
+    // The client polls for the outcome, see `get_status`.
+    let result = link_discord_account(identity, code, verifier).await;
+    let outcome = match result {
+        Ok(_) => LoginOutcome::Linked,
+        Err(_) => LoginOutcome::Failed,
+    };
+    state.lock().await.finish(query.state.clone(), outcome);
+    result
+}
+
+pub(crate) fn discord_client() -> Result<DiscordClient, StatusCode> {
+    Ok(BasicClient::new(ClientId::new(APPLICATION_ID.into()))
         .set_client_secret(ClientSecret::new(APP_SECRET.into()))
+        .set_auth_uri(
+            AuthUrl::new("https://discord.com/oauth2/authorize".into())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        )
         .set_redirect_uri(
             RedirectUrl::new("http://localhost:42069/".into())
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
@@ -43,17 +80,29 @@ pub(crate) async fn disco_auth(
         .set_token_uri(
             TokenUrl::new("https://discord.com/api/oauth2/token".into())
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-        );
+        ))
+}
+
+async fn link_discord_account(
+    identity: Identity,
+    code: String,
+    verifier: PkceCodeVerifier,
+) -> Result<String, StatusCode> {
+    let client = discord_client()?;
 
     let http_client = reqwest::ClientBuilder::new()
         .redirect(reqwest::redirect::Policy::none())
         .build()
-        .expect("Client should build");
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let token = client
-        .exchange_code(AuthorizationCode::new(query.code.clone()))
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(verifier)
         .request_async(&http_client)
         .await
-        .unwrap();
+        .map_err(|err| {
+            eprintln!("Exchanging Discord authorization code failed: {}", err);
+            StatusCode::BAD_GATEWAY
+        })?;
 
     // Create a request to Discord's user API endpoint
     let user_response = http_client
@@ -77,8 +126,48 @@ pub(crate) async fn disco_auth(
         .json()
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let avatar_url = user_data.avatar.map(|hash| {
+        format!(
+            "https://cdn.discordapp.com/avatars/{}/{}.png",
+            user_data.id, hash
+        )
+    });
     let username = user_data.username;
-    db().reducers.set_name(username.clone()).unwrap();
-    // Format the response with user information
-    Ok(format!("Welcome {}", username))
+    // Reducer calls only report whether they were sent, so wait for the module's verdict.
+    let (sender, receiver) = oneshot::channel();
+    let mut sender = Some(sender);
+    let callback = db()
+        .reducers
+        .on_link_external_account(move |ctx, target, _, _, _, _| {
+            if let Some(sender) = sender.take_if(|_| *target == identity) {
+                let _ = sender.send(ctx.event.status.clone());
+            }
+        });
+    let called = db().reducers.link_external_account(
+        identity,
+        "discord".into(),
+        user_data.id,
+        username.clone(),
+        avatar_url,
+    );
+    let status = match called {
+        Ok(()) => time::timeout(LINK_TIMEOUT, receiver)
+            .await
+            .ok()
+            .and_then(Result::ok),
+        Err(_) => None,
+    };
+    db().reducers.remove_on_link_external_account(callback);
+    match status {
+        // Format the response with user information
+        Some(Status::Committed) => Ok(format!("Welcome {}", username)),
+        Some(Status::Failed(reason)) => {
+            eprintln!(
+                "Linking Discord account for {} failed: {}",
+                identity, reason
+            );
+            Err(StatusCode::CONFLICT)
+        }
+        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
 }
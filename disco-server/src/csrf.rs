@@ -1,57 +1,197 @@
 use axum::extract::{Path, State};
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use oauth2::{CsrfToken, PkceCodeChallenge, PkceCodeVerifier, Scope};
 use rand::{Rng, rng};
+use reqwest::StatusCode;
+use sha2::Sha256;
+use spacetimedb_sdk::Identity;
 use std::{
     collections::HashMap,
+    fmt,
     time::{Duration, Instant},
 };
 use tokio::time;
 
-use crate::SharedCache;
+use crate::{SharedCache, authorize::discord_client};
 
 const EXPIRE_IN_SECS: Duration = Duration::from_secs(1800);
+/// Length of the random part of a state, which the HMAC tag follows.
+const NONCE_LEN: usize = 16;
 
-#[derive(Clone)]
+type HmacSha256 = Hmac<Sha256>;
+
+/// How a login that got back from Discord ended, which clients poll for.
+#[derive(Clone, Copy)]
+pub(crate) enum LoginOutcome {
+    Linked,
+    Denied,
+    Failed,
+}
+
+impl LoginOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            LoginOutcome::Linked => "linked",
+            LoginOutcome::Denied => "denied",
+            LoginOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// Why a state Discord sent back was turned away.
+#[derive(Debug, PartialEq)]
+pub(crate) enum StateError {
+    /// The state was never handed out, or its login was cancelled.
+    Unknown,
+    /// The state was redeemed before.
+    Replayed,
+    Expired,
+    /// The state's tag doesn't match the identity it names.
+    Forged,
+}
+
+impl StateError {
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            StateError::Replayed => StatusCode::CONFLICT,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            StateError::Unknown => "unknown state",
+            StateError::Replayed => "state was already used",
+            StateError::Expired => "state expired",
+            StateError::Forged => "state does not match its identity",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// A login whose user hasn't been redirected back from Discord yet.
+struct PendingLogin {
+    /// Proves to Discord that the code is redeemed by whoever started the login.
+    verifier: PkceCodeVerifier,
+    created: Instant,
+}
+
+/// Logins by their state, which names the identity that started the login
+/// and is signed for it, so the identity can't be swapped for another one.
 pub(crate) struct CsrfCache {
-    by_identity: HashMap<String, (String, Instant)>,
-    by_state: HashMap<String, String>,
+    /// Key the states are signed with.
+    key: [u8; 32],
+    pending: HashMap<String, PendingLogin>,
+    /// States that were redeemed already, so replays can be told apart and rejected,
+    /// and clients learn their login is in progress until it has an outcome.
+    redeemed: HashMap<String, Instant>,
+    outcomes: HashMap<String, (LoginOutcome, Instant)>,
 }
 
 impl CsrfCache {
     pub(crate) fn new() -> Self {
         Self {
-            by_identity: HashMap::new(),
-            by_state: HashMap::new(),
+            key: rng().random(),
+            pending: HashMap::new(),
+            redeemed: HashMap::new(),
+            outcomes: HashMap::new(),
         }
     }
-    pub(crate) fn insert(&mut self, id: String, state: String) {
-        let instant = Instant::now();
-        self.by_identity
-            .insert(id.clone(), (state.clone(), instant));
-        self.by_state.insert(state, id);
+
+    /// Hands out the state of a new login for `identity`.
+    pub(crate) fn begin(&mut self, identity: Identity, verifier: PkceCodeVerifier) -> String {
+        let nonce: [u8; NONCE_LEN] = rng().random();
+        let tag = self.mac(&identity, &nonce).finalize().into_bytes();
+        let state = format!(
+            "{}.{}",
+            identity.to_hex(),
+            BASE64_URL_SAFE_NO_PAD.encode([&nonce[..], &tag[..]].concat())
+        );
+        self.pending.insert(
+            state.clone(),
+            PendingLogin {
+                verifier,
+                created: Instant::now(),
+            },
+        );
+        state
+    }
+
+    /// Redeems a state Discord sent back, returning the identity it was signed for
+    /// and the PKCE verifier of its login. Each state can only be redeemed once.
+    pub(crate) fn redeem(
+        &mut self,
+        state: &str,
+    ) -> Result<(Identity, PkceCodeVerifier), StateError> {
+        if self.redeemed.contains_key(state) {
+            return Err(StateError::Replayed);
+        }
+        let identity = self.verify(state).ok_or(StateError::Forged)?;
+        let login = self.pending.remove(state).ok_or(StateError::Unknown)?;
+        // Expired states aren't remembered, so they show as unknown rather than in progress.
+        if login.created.elapsed() > EXPIRE_IN_SECS {
+            return Err(StateError::Expired);
+        }
+        self.redeemed.insert(state.to_string(), Instant::now());
+        Ok((identity, login.verifier))
     }
 
-    pub(crate) fn get_by_identity(&self, id: &str) -> Option<(String, Instant)> {
-        self.by_identity.get(id).cloned()
+    /// Drops the login with `state`, so it can't be redeemed anymore.
+    pub(crate) fn cancel(&mut self, state: &str) {
+        self.pending.remove(state);
     }
 
-    pub(crate) fn take_by_state(&mut self, state: &str) -> Option<String> {
-        self.by_state.remove(state).map(|id| {
-            self.by_identity.remove(&id);
-            id
-        })
+    pub(crate) fn finish(&mut self, state: String, outcome: LoginOutcome) {
+        self.outcomes.insert(state, (outcome, Instant::now()));
+    }
+
+    /// Where the login with `state` stands: "pending" until Discord redirects back,
+    /// "in_progress" while its account is being linked, then its outcome,
+    /// or "unknown" if it never started or expired.
+    pub(crate) fn status(&self, state: &str) -> &'static str {
+        if self.pending.contains_key(state) {
+            "pending"
+        } else if let Some((outcome, _)) = self.outcomes.get(state) {
+            outcome.as_str()
+        } else if self.redeemed.contains_key(state) {
+            "in_progress"
+        } else {
+            "unknown"
+        }
     }
 
     pub(crate) fn cleanup_expired(&mut self) {
         let now = Instant::now();
-        self.by_identity.retain(|_, (state, created)| {
-            if now.duration_since(*created) > EXPIRE_IN_SECS {
-                self.by_state.remove(state);
-                false
-            } else {
-                true
-            }
-        });
+        self.pending
+            .retain(|_, login| now.duration_since(login.created) <= EXPIRE_IN_SECS);
+        // Expired states are rejected anyway, so replays only need catching until then.
+        self.redeemed
+            .retain(|_, redeemed| now.duration_since(*redeemed) <= EXPIRE_IN_SECS);
+        self.outcomes
+            .retain(|_, (_, finished)| now.duration_since(*finished) <= EXPIRE_IN_SECS);
+    }
+
+    fn mac(&self, identity: &Identity, nonce: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(identity.to_hex().as_bytes());
+        mac.update(nonce);
+        mac
+    }
+
+    /// Returns the identity `state` names, if its tag was made for it.
+    fn verify(&self, state: &str) -> Option<Identity> {
+        let (identity, signed) = state.split_once('.')?;
+        let identity = Identity::from_hex(identity).ok()?;
+        let bytes = BASE64_URL_SAFE_NO_PAD.decode(signed).ok()?;
+        if bytes.len() <= NONCE_LEN {
+            return None;
+        }
+        let (nonce, tag) = bytes.split_at(NONCE_LEN);
+        self.mac(&identity, nonce).verify_slice(tag).ok()?;
+        Some(identity)
     }
 }
 
@@ -63,23 +203,97 @@ pub(crate) async fn start_cleanup(cache: SharedCache) {
     }
 }
 
+/// Starts a Discord login for `identity`, returning the URL to send the user to.
+/// Clients poll for the outcome and cancel the login with its `state` parameter.
 pub(crate) async fn get_csrf(
     State(state): State<SharedCache>,
     Path(identity): Path<String>,
-) -> String {
-    let mut cache = state.lock().await;
-    let now = Instant::now();
-    if let Some((csrf, timestamp)) = cache.get_by_identity(&identity) {
-        if now.duration_since(timestamp) < EXPIRE_IN_SECS {
-            return csrf.to_string();
-        }
-    }
-    let csrf = generate_csrf();
-    cache.insert(identity, csrf.clone());
-    csrf
+) -> Result<String, StatusCode> {
+    let identity = Identity::from_hex(&identity).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let client = discord_client()?;
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let csrf = state.lock().await.begin(identity, verifier);
+    let (url, _) = client
+        .authorize_url(|| CsrfToken::new(csrf))
+        .add_scope(Scope::new("identify".into()))
+        .add_scope(Scope::new("guilds.members.read".into()))
+        .set_pkce_challenge(challenge)
+        .url();
+    Ok(url.to_string())
+}
+
+/// Gives up on the login with `state`, once its user cancelled it in the client.
+pub(crate) async fn cancel_login(
+    State(cache): State<SharedCache>,
+    Path(state): Path<String>,
+) -> StatusCode {
+    cache.lock().await.cancel(&state);
+    StatusCode::NO_CONTENT
+}
+
+pub(crate) async fn get_status(
+    State(cache): State<SharedCache>,
+    Path(state): Path<String>,
+) -> &'static str {
+    cache.lock().await.status(&state)
 }
 
-fn generate_csrf() -> String {
-    let random_bytes: Vec<u8> = (0..16).map(|_| rng().random::<u8>()).collect();
-    BASE64_URL_SAFE_NO_PAD.encode(random_bytes)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn begin(cache: &mut CsrfCache) -> String {
+        let (_, verifier) = PkceCodeChallenge::new_random_sha256();
+        cache.begin(Identity::ZERO, verifier)
+    }
+
+    #[test]
+    fn states_are_redeemed_once() {
+        let mut cache = CsrfCache::new();
+        let state = begin(&mut cache);
+        assert_eq!(cache.status(&state), "pending");
+        let (identity, _) = cache.redeem(&state).unwrap();
+        assert_eq!(identity, Identity::ZERO);
+        assert_eq!(cache.redeem(&state).err(), Some(StateError::Replayed));
+    }
+
+    #[test]
+    fn states_are_bound_to_their_identity() {
+        let mut cache = CsrfCache::new();
+        let state = begin(&mut cache);
+        let (_, signed) = state.split_once('.').unwrap();
+        let swapped = format!("{}.{}", Identity::ONE.to_hex(), signed);
+        assert_eq!(cache.redeem(&swapped).err(), Some(StateError::Forged));
+        assert_eq!(cache.redeem("made-up").err(), Some(StateError::Forged));
+        assert!(cache.redeem(&state).is_ok());
+    }
+
+    #[test]
+    fn redeemed_states_are_in_progress_until_finished() {
+        let mut cache = CsrfCache::new();
+        let state = begin(&mut cache);
+        assert!(cache.redeem(&state).is_ok());
+        assert_eq!(cache.status(&state), "in_progress");
+        cache.finish(state.clone(), LoginOutcome::Linked);
+        assert_eq!(cache.status(&state), "linked");
+    }
+
+    #[test]
+    fn expired_states_are_rejected() {
+        let mut cache = CsrfCache::new();
+        let state = begin(&mut cache);
+        let login = cache.pending.get_mut(&state).unwrap();
+        login.created -= EXPIRE_IN_SECS + Duration::from_secs(1);
+        assert_eq!(cache.redeem(&state).err(), Some(StateError::Expired));
+        assert_eq!(cache.status(&state), "unknown");
+    }
+
+    #[test]
+    fn unknown_and_cancelled_states_are_rejected() {
+        let mut cache = CsrfCache::new();
+        let state = begin(&mut cache);
+        cache.cancel(&state);
+        assert_eq!(cache.status(&state), "unknown");
+        assert_eq!(cache.redeem(&state).err(), Some(StateError::Unknown));
+    }
 }
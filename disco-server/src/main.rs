@@ -1,10 +1,13 @@
-use axum::{Router, routing::get};
+use axum::{
+    Router,
+    routing::{get, post},
+};
 use std::sync::{Arc, OnceLock};
 use tokio::sync::Mutex;
 
 use crate::{
     authorize::disco_auth,
-    csrf::{CsrfCache, get_csrf, start_cleanup},
+    csrf::{CsrfCache, cancel_login, get_csrf, get_status, start_cleanup},
     module_bindings::DbConnection,
     stdb::connect_to_db,
 };
@@ -46,5 +49,7 @@ fn router() -> Router {
     Router::new()
         .route("/", get(disco_auth))
         .route("/csrf/{identity}", get(get_csrf))
+        .route("/status/{state}", get(get_status))
+        .route("/cancel/{state}", post(cancel_login))
         .with_state(cache)
 }
@@ -1,4 +1,4 @@
-use spacetimedb_sdk::{Error, Identity};
+use spacetimedb_sdk::{Error, Identity, credentials};
 
 use crate::module_bindings::{DbConnection, ErrorContext};
 
@@ -11,12 +11,21 @@ pub(crate) fn connect_to_db() -> DbConnection {
         .on_connect_error(on_connect_error)
         .with_module_name(DB_NAME)
         .with_uri(HOST)
+        .with_token(creds_store().load().expect("Error loading credentials"))
         .build()
         .expect("Failed to connect")
 }
 
-pub(crate) fn on_connected(_ctx: &DbConnection, _identity: Identity, _token: &str) {
-    println!("Connected to SpacetimeDB.");
+// The identity has to stay the same across restarts, since it's registered as a trusted service.
+fn creds_store() -> credentials::File {
+    credentials::File::new("disco-server")
+}
+
+pub(crate) fn on_connected(_ctx: &DbConnection, identity: Identity, token: &str) {
+    if let Err(e) = creds_store().save(token) {
+        eprintln!("Failed to save credentials: {:?}", e);
+    }
+    println!("Connected to SpacetimeDB as {}.", identity);
 }
 
 pub(crate) fn on_connect_error(_ctx: &ErrorContext, err: Error) {
@@ -0,0 +1,82 @@
+use spacetimedb::{
+    table, view, Identity, ReducerContext, SpacetimeType, Table, Timestamp, ViewContext,
+};
+
+use crate::roles::{permissions_of, BAN, DELETE_ANY, MANAGE_ROLES, MANAGE_SETTINGS, MUTE};
+
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    Rename,
+    EditMessage,
+    DeleteMessage,
+    Mute,
+    Unmute,
+    Ban,
+    Unban,
+    CreateChannel,
+    InviteToChannel,
+    JoinPrivateChannel,
+    ChangeRetention,
+    CreateRole,
+    ChangeRole,
+    DeleteRole,
+    GrantRole,
+    RevokeRole,
+    ChangeSettings,
+    RegisterService,
+    RemoveService,
+    LinkAccount,
+    UnlinkAccount,
+}
+
+/// Trail of privileged actions, served to moderators by `moderation_log`.
+#[table(name = audit_log)]
+pub struct AuditEntry {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[index(btree)]
+    pub actor: Identity,
+    /// The user the action was taken against, if any.
+    pub target: Option<Identity>,
+    pub action: AuditAction,
+    /// Why the action was taken, as given by the actor.
+    pub reason: String,
+    /// What the action changed, e.g. the old and new name of a rename.
+    pub details: String,
+    pub at: Timestamp,
+}
+
+#[view(name = moderation_log, public)]
+/// The whole audit log for moderators, and nothing for anyone else.
+pub fn moderation_log(ctx: &ViewContext) -> Vec<AuditEntry> {
+    let moderator_permissions = DELETE_ANY | MUTE | BAN | MANAGE_ROLES | MANAGE_SETTINGS;
+    if permissions_of(ctx, ctx.sender) & moderator_permissions == 0 {
+        return Vec::new();
+    }
+    // Views can't scan whole tables, so take every entry through the actor index instead.
+    ctx.db
+        .audit_log()
+        .actor()
+        .filter(Identity::ZERO..)
+        .collect()
+}
+
+/// Appends an entry for an action the caller just took.
+pub fn record(
+    ctx: &ReducerContext,
+    action: AuditAction,
+    target: Option<Identity>,
+    reason: &str,
+    details: String,
+) {
+    ctx.db.audit_log().insert(AuditEntry {
+        id: 0,
+        actor: ctx.sender,
+        target,
+        action,
+        reason: reason.to_string(),
+        details,
+        at: ctx.timestamp,
+    });
+}
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+
+use spacetimedb::{
+    reducer, table, view, Identity, ReducerContext, SpacetimeType, Table, Timestamp, ViewContext,
+};
+
+use crate::audit::{record, AuditAction};
+use crate::read_markers::clear_marker;
+use crate::roles::{
+    has_permission, permissions_of, require_permission, CREATE_CHANNELS, MANAGE_CHANNELS,
+};
+use crate::sanctions::check_not_banned;
+
+/// Name of the channel created at `init` that every new user joins.
+pub const DEFAULT_CHANNEL: &str = "general";
+
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelVisibility {
+    /// Listed for everyone and joinable by anyone.
+    Public,
+    /// Only joinable by being invited by an existing member.
+    Private,
+}
+
+#[table(name = channel)]
+/// Served by `my_channels`, which hides private channels from outsiders.
+pub struct Channel {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[unique]
+    pub name: String,
+    pub topic: String,
+    #[index(btree)]
+    pub created_by: Identity,
+    pub created_at: Timestamp,
+    pub visibility: ChannelVisibility,
+    /// Sequence number of the latest message sent to the channel.
+    pub last_seq: u64,
+}
+
+#[table(
+    name = channel_member,
+    index(name = channel_and_member, btree(columns = [channel_id, member]))
+)]
+/// Served by `my_channel_members`.
+pub struct ChannelMember {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[index(btree)]
+    pub member: Identity,
+    pub channel_id: u64,
+    pub joined_at: Timestamp,
+}
+
+#[view(name = my_channels, public)]
+/// Public channels, private ones the calling identity is a member of,
+/// and every private channel for channel managers, who may join them.
+pub fn my_channels(ctx: &ViewContext) -> Vec<Channel> {
+    let sees_private = permissions_of(ctx, ctx.sender) & MANAGE_CHANNELS != 0;
+    let joined: HashSet<u64> = joined_channel_ids(ctx, ctx.sender).collect();
+    // Views can't scan whole tables, so take every channel through the creator index instead.
+    ctx.db
+        .channel()
+        .created_by()
+        .filter(Identity::ZERO..)
+        .filter(|channel| {
+            channel.visibility == ChannelVisibility::Public
+                || sees_private
+                || joined.contains(&channel.id)
+        })
+        .collect()
+}
+
+#[view(name = my_channel_members, public)]
+/// Every membership of the channels the calling identity is a member of.
+pub fn my_channel_members(ctx: &ViewContext) -> Vec<ChannelMember> {
+    joined_channel_ids(ctx, ctx.sender)
+        .flat_map(|channel_id| {
+            ctx.db
+                .channel_member()
+                .channel_and_member()
+                .filter(channel_id)
+        })
+        .collect()
+}
+
+#[reducer]
+/// Clients invoke this reducer to create a channel, which they join right away.
+pub fn create_channel(
+    ctx: &ReducerContext,
+    name: String,
+    topic: String,
+    visibility: ChannelVisibility,
+) -> Result<(), String> {
+    check_not_banned(ctx, ctx.sender)?;
+    require_permission(ctx, CREATE_CHANNELS)?;
+    let name = validate_channel_name(name)?;
+    if ctx.db.channel().name().find(&name).is_some() {
+        return Err(format!("Channel #{} already exists", name));
+    }
+    let channel = ctx.db.channel().insert(Channel {
+        id: 0,
+        name,
+        topic,
+        created_by: ctx.sender,
+        created_at: ctx.timestamp,
+        visibility,
+        last_seq: 0,
+    });
+    record(
+        ctx,
+        AuditAction::CreateChannel,
+        None,
+        "",
+        format!("#{} ({:?})", channel.name, channel.visibility),
+    );
+    add_member(ctx, channel.id, ctx.sender);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to join a public channel.
+/// Channel managers may join private channels as well.
+pub fn join_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    let channel = find_channel(ctx, channel_id)?;
+    if channel.visibility == ChannelVisibility::Private
+        && !has_permission(ctx, ctx.sender, MANAGE_CHANNELS)
+    {
+        return Err(format!("Channel #{} is invite-only", channel.name));
+    }
+    if is_member(ctx, channel_id, ctx.sender) {
+        return Err(format!("Already a member of #{}", channel.name));
+    }
+    if channel.visibility == ChannelVisibility::Private {
+        record(
+            ctx,
+            AuditAction::JoinPrivateChannel,
+            None,
+            "",
+            format!("#{}", channel.name),
+        );
+    }
+    add_member(ctx, channel_id, ctx.sender);
+    Ok(())
+}
+
+#[reducer]
+/// Members and channel managers invoke this reducer to add another user to a channel,
+/// including private ones.
+pub fn invite_to_channel(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    invitee: Identity,
+) -> Result<(), String> {
+    let channel = find_channel(ctx, channel_id)?;
+    if !is_member(ctx, channel_id, ctx.sender) && !has_permission(ctx, ctx.sender, MANAGE_CHANNELS)
+    {
+        return Err(format!(
+            "Only members of #{} can invite others",
+            channel.name
+        ));
+    }
+    if is_member(ctx, channel_id, invitee) {
+        return Err(format!("User is already a member of #{}", channel.name));
+    }
+    record(
+        ctx,
+        AuditAction::InviteToChannel,
+        Some(invitee),
+        "",
+        format!("#{}", channel.name),
+    );
+    add_member(ctx, channel_id, invitee);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to stop receiving and sending messages in a channel.
+pub fn leave_channel(ctx: &ReducerContext, channel_id: u64) -> Result<(), String> {
+    let channel = find_channel(ctx, channel_id)?;
+    let removed = ctx
+        .db
+        .channel_member()
+        .channel_and_member()
+        .delete((channel_id, ctx.sender));
+    if removed == 0 {
+        Err(format!("Not a member of #{}", channel.name))
+    } else {
+        clear_marker(ctx, ctx.sender, channel_id);
+        Ok(())
+    }
+}
+
+/// Takes a channel name and checks if it's acceptable, normalizing it to lowercase.
+fn validate_channel_name(name: String) -> Result<String, String> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        Err("Channel names must not be empty".to_string())
+    } else if name.chars().count() > 32 {
+        Err("Channel names must be at most 32 characters".to_string())
+    } else if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        Err("Channel names may only contain letters, digits, '-' and '_'".to_string())
+    } else {
+        Ok(name)
+    }
+}
+
+pub fn find_channel(ctx: &ReducerContext, channel_id: u64) -> Result<Channel, String> {
+    ctx.db
+        .channel()
+        .id()
+        .find(channel_id)
+        .ok_or_else(|| format!("No channel with id {}", channel_id))
+}
+
+pub fn is_member(ctx: &ReducerContext, channel_id: u64, identity: Identity) -> bool {
+    ctx.db
+        .channel_member()
+        .channel_and_member()
+        .filter((channel_id, identity))
+        .next()
+        .is_some()
+}
+
+/// Ids of the channels `identity` is a member of, for views to serve their content from.
+pub fn joined_channel_ids(ctx: &ViewContext, identity: Identity) -> impl Iterator<Item = u64> + '_ {
+    ctx.db
+        .channel_member()
+        .member()
+        .filter(identity)
+        .map(|member| member.channel_id)
+}
+
+pub fn add_member(ctx: &ReducerContext, channel_id: u64, identity: Identity) {
+    ctx.db.channel_member().insert(ChannelMember {
+        id: 0,
+        member: identity,
+        channel_id,
+        joined_at: ctx.timestamp,
+    });
+}
+
+/// Creates the default channel if it doesn't exist yet and returns it.
+pub fn default_channel(ctx: &ReducerContext) -> Channel {
+    if let Some(channel) = ctx.db.channel().name().find(DEFAULT_CHANNEL.to_string()) {
+        return channel;
+    }
+    ctx.db.channel().insert(Channel {
+        id: 0,
+        name: DEFAULT_CHANNEL.to_string(),
+        topic: "Everyone's here".to_string(),
+        created_by: ctx.sender,
+        created_at: ctx.timestamp,
+        visibility: ChannelVisibility::Public,
+        last_seq: 0,
+    })
+}
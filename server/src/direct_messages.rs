@@ -0,0 +1,62 @@
+use spacetimedb::{reducer, table, view, Identity, ReducerContext, Table, Timestamp, ViewContext};
+
+use crate::{
+    rate_limit::consume_token,
+    roles::{require_permission, SEND},
+    sanctions::check_can_send,
+    user,
+    validation::{check_name_set, validate_message},
+};
+
+/// Private one-to-one messages, served by `my_direct_messages`.
+#[table(name = direct_message)]
+pub struct DirectMessage {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[index(btree)]
+    pub sender: Identity,
+    #[index(btree)]
+    pub recipient: Identity,
+    pub sent: Timestamp,
+    pub text: String,
+}
+
+#[view(name = my_direct_messages, public)]
+/// Every direct message the calling identity has sent or received.
+pub fn my_direct_messages(ctx: &ViewContext) -> Vec<DirectMessage> {
+    let sent = ctx.db.direct_message().sender().filter(ctx.sender);
+    let received = ctx
+        .db
+        .direct_message()
+        .recipient()
+        .filter(ctx.sender)
+        // Notes to self were already picked up as sent messages.
+        .filter(|dm| dm.sender != ctx.sender);
+    sent.chain(received).collect()
+}
+
+#[reducer]
+/// Clients invoke this reducer to privately message another user.
+pub fn send_direct_message(
+    ctx: &ReducerContext,
+    recipient: Identity,
+    text: String,
+) -> Result<(), String> {
+    require_permission(ctx, SEND)?;
+    check_can_send(ctx, ctx.sender)?;
+    check_name_set(ctx)?;
+    if ctx.db.user().identity().find(recipient).is_none() {
+        return Err("Cannot message unknown user".to_string());
+    }
+    let text = validate_message(ctx, text)?;
+    consume_token(ctx)?;
+    ctx.db.direct_message().insert(DirectMessage {
+        id: 0,
+        sender: ctx.sender,
+        recipient,
+        sent: ctx.timestamp,
+        text,
+    });
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use spacetimedb::{
+    reducer, table, Identity, ReducerContext, ScheduleAt, Table, TimeDuration, Timestamp,
+};
+
+use crate::audit::{record, AuditAction};
+use crate::sanctions::check_not_banned;
+use crate::services::{require_capability, LINK_ACCOUNTS};
+use crate::{rename, user};
+
+/// How long users have to finish a login they requested.
+const LINK_REQUEST_TTL_MICROS: i64 = 30 * 60 * 1_000_000;
+/// How often expired link requests are removed: every 10 minutes.
+const PRUNE_INTERVAL_MICROS: i64 = 10 * 60 * 1_000_000;
+
+#[table(name = external_account, public)]
+/// Accounts users have proven they own with other providers, such as Discord.
+/// Each account is linked to at most one user, which its unique `key` enforces,
+/// and each user has at most one account per provider, which `link_external_account` enforces.
+pub struct ExternalAccount {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    /// The provider and the account's id with it, e.g. "discord:80351110224678912".
+    #[unique]
+    key: String,
+    #[index(btree)]
+    identity: Identity,
+    /// Lowercase name of the provider, e.g. "discord".
+    provider: String,
+    /// The account's id with the provider, which stays the same across renames.
+    external_id: String,
+    display_name: String,
+    avatar_url: Option<String>,
+    linked_at: Timestamp,
+}
+
+#[table(name = account_link_request)]
+/// Logins with other providers that users started, one per user.
+/// Only users can request links, so services can't link accounts to users who aren't signing in.
+pub struct AccountLinkRequest {
+    #[primary_key]
+    identity: Identity,
+    /// Lowercase name of the provider the user is signing in with.
+    provider: String,
+    requested_at: Timestamp,
+}
+
+#[table(name = link_request_prune_schedule, scheduled(prune_link_requests))]
+pub struct LinkRequestPruneSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+#[reducer]
+/// Clients invoke this reducer when they start signing in with `provider`,
+/// replacing any login they started before.
+pub fn request_account_link(ctx: &ReducerContext, provider: String) -> Result<(), String> {
+    check_not_banned(ctx, ctx.sender)?;
+    let provider = provider.trim().to_lowercase();
+    if provider.is_empty() {
+        return Err("Linked accounts need a provider".to_string());
+    }
+    ctx.db.account_link_request().identity().delete(ctx.sender);
+    ctx.db.account_link_request().insert(AccountLinkRequest {
+        identity: ctx.sender,
+        provider,
+        requested_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Trusted services invoke this reducer once a user has proven they own an account
+/// with another provider, e.g. by signing in with Discord.
+/// `target` must have requested the link, see `request_account_link`, which uses up their request.
+/// Users without a name yet take the account's display name, if it's available.
+pub fn link_external_account(
+    ctx: &ReducerContext,
+    target: Identity,
+    provider: String,
+    external_id: String,
+    display_name: String,
+    avatar_url: Option<String>,
+) -> Result<(), String> {
+    require_capability(ctx, LINK_ACCOUNTS)?;
+    check_not_banned(ctx, target)?;
+    let provider = provider.trim().to_lowercase();
+    let external_id = external_id.trim().to_string();
+    if provider.is_empty() || external_id.is_empty() {
+        return Err("Linked accounts need a provider and an id".to_string());
+    }
+    // The provider ends where the account's `key` has its first ':'.
+    if provider.contains(':') {
+        return Err("Provider names cannot contain ':'".to_string());
+    }
+    take_link_request(ctx, target, &provider)?;
+    let key = format!("{}:{}", provider, external_id);
+    let user = ctx
+        .db
+        .user()
+        .identity()
+        .find(target)
+        .ok_or_else(|| "Cannot link an account to unknown user".to_string())?;
+    let existing = ctx.db.external_account().key().find(&key);
+    if existing
+        .as_ref()
+        .is_some_and(|account| account.identity != target)
+    {
+        return Err(format!(
+            "This {} account is already linked to another user",
+            provider
+        ));
+    }
+    if existing.is_none() && find_account(ctx, target, &provider).is_some() {
+        return Err(format!(
+            "Unlink your current {} account before linking another one",
+            provider
+        ));
+    }
+    // Users keep the names they picked, and stay unnamed if the account's name can't be used.
+    if user.name.is_none() {
+        if let Err(err) = rename(ctx, target, display_name.clone()) {
+            log::info!(
+                "Not naming {} after their {} account: {}",
+                target,
+                provider,
+                err
+            );
+        }
+    }
+    record(
+        ctx,
+        AuditAction::LinkAccount,
+        Some(target),
+        "",
+        format!("{} account {} ({})", provider, external_id, display_name),
+    );
+    match existing {
+        // Signing in again refreshes how the account is shown.
+        Some(account) => {
+            ctx.db.external_account().id().update(ExternalAccount {
+                display_name,
+                avatar_url,
+                ..account
+            });
+        }
+        None => {
+            ctx.db.external_account().insert(ExternalAccount {
+                id: 0,
+                key,
+                identity: target,
+                provider,
+                external_id,
+                display_name,
+                avatar_url,
+                linked_at: ctx.timestamp,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Called by the `link_request_prune_schedule` to remove link requests that expired unused.
+pub fn prune_link_requests(
+    ctx: &ReducerContext,
+    _schedule: LinkRequestPruneSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Only the module itself can prune link requests".to_string());
+    }
+    let expired: Vec<Identity> = ctx
+        .db
+        .account_link_request()
+        .iter()
+        .filter(|request| is_expired(ctx, request))
+        .map(|request| request.identity)
+        .collect();
+    for identity in expired {
+        ctx.db.account_link_request().identity().delete(identity);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to unlink their account with `provider`.
+pub fn unlink_external_account(ctx: &ReducerContext, provider: String) -> Result<(), String> {
+    let provider = provider.trim().to_lowercase();
+    let account = find_account(ctx, ctx.sender, &provider)
+        .ok_or_else(|| format!("You have no linked {} account", provider))?;
+    record(
+        ctx,
+        AuditAction::UnlinkAccount,
+        Some(ctx.sender),
+        "",
+        format!(
+            "{} account {} ({})",
+            provider, account.external_id, account.display_name
+        ),
+    );
+    ctx.db.external_account().id().delete(account.id);
+    Ok(())
+}
+
+/// Schedules expired link requests to be removed periodically.
+pub fn init_schedule(ctx: &ReducerContext) {
+    ctx.db
+        .link_request_prune_schedule()
+        .insert(LinkRequestPruneSchedule {
+            scheduled_id: 0,
+            scheduled_at: TimeDuration::from_micros(PRUNE_INTERVAL_MICROS).into(),
+        });
+}
+
+/// Uses up the request of `identity` to link an account with `provider`.
+/// Expired requests are left for `prune_link_requests`, as failing rolls back any removal.
+fn take_link_request(
+    ctx: &ReducerContext,
+    identity: Identity,
+    provider: &str,
+) -> Result<(), String> {
+    let request = ctx
+        .db
+        .account_link_request()
+        .identity()
+        .find(identity)
+        .filter(|request| request.provider == provider)
+        .ok_or_else(|| format!("{} did not start signing in with {}", identity, provider))?;
+    if is_expired(ctx, &request) {
+        return Err("This login expired".to_string());
+    }
+    ctx.db.account_link_request().identity().delete(identity);
+    Ok(())
+}
+
+fn is_expired(ctx: &ReducerContext, request: &AccountLinkRequest) -> bool {
+    let expires_at = request.requested_at + TimeDuration::from_micros(LINK_REQUEST_TTL_MICROS);
+    expires_at.time_duration_since(ctx.timestamp).is_none()
+}
+
+fn find_account(
+    ctx: &ReducerContext,
+    identity: Identity,
+    provider: &str,
+) -> Option<ExternalAccount> {
+    ctx.db
+        .external_account()
+        .identity()
+        .filter(identity)
+        .find(|account| account.provider == provider)
+}
@@ -1,28 +1,121 @@
-use spacetimedb::{table, reducer, Table, ReducerContext, Identity, Timestamp};
+//! Chat module. Tables holding anything users shouldn't all see are private,
+//! and clients subscribe to views that serve each caller only their share of them instead.
+
+use spacetimedb::{table, reducer, view, Table, ReducerContext, ViewContext, Identity, Timestamp};
+
+use crate::audit::{record, AuditAction};
+use crate::channels::{add_member, channel, default_channel, find_channel, is_member, joined_channel_ids, Channel};
+use crate::presence::PresenceStatus;
+use crate::rate_limit::consume_token;
+use crate::mentions::{clear_mentions, record_mentions};
+use crate::names::{claim_name, normalize_name};
+use crate::reactions::clear_reactions;
+use crate::read_markers::advance_marker;
+use crate::roles::{has_permission, require_permission, DELETE_ANY, SEND};
+use crate::sanctions::{check_can_send, check_not_banned};
+use crate::services::is_trusted_service;
+use crate::typing_indicators::{clear_typing, stop_typing};
+use crate::validation::{check_name_set, validate_message};
+
+mod audit;
+mod channels;
+mod direct_messages;
+mod external_accounts;
+mod mentions;
+mod names;
+mod presence;
+mod rate_limit;
+mod reactions;
+mod read_markers;
+mod retention;
+mod roles;
+mod sanctions;
+mod services;
+mod typing_indicators;
+mod validation;
 
 #[table(name = user, public)]
 pub struct User {
     #[primary_key]
     identity: Identity,
     name: Option<String>,
+    /// Whether any of the user's connections are open, unless they are invisible.
     online: bool,
+    /// When the user was last online, or went offline.
+    last_seen: Timestamp,
+    status: PresenceStatus,
+    status_text: Option<String>,
 }
 
-#[table(name = message, public)]
+/// Messages sent to channels, served by `my_channel_messages`.
+#[table(
+    name = message,
+    index(name = channel_and_seq, btree(columns = [channel_id, seq]))
+)]
 pub struct Message {
     #[primary_key]
     #[auto_inc]
     id: u64,
+    channel_id: u64,
+    /// Position of the message in its channel, starting at 1,
+    /// so clients can subscribe to a page of history at a time.
+    seq: u64,
     sender: Identity,
     sent: Timestamp,
     text: String,
+    /// The first message of the thread this message replies to.
+    reply_to: Option<u64>,
+    edited_at: Option<Timestamp>,
+    /// Deleted messages are kept as tombstones with their text cleared.
+    deleted: bool,
+}
+
+#[view(name = my_channel_messages, public)]
+/// Every message of the channels the calling identity is a member of.
+/// Views can't take arguments, so clients page through it with `WHERE channel_id = .. AND seq ..`,
+/// which narrows what they receive but not what the view reads: each evaluation reads the whole
+/// history of every joined channel. That history is capped by the channel's retention policy,
+/// 1000 messages by default, which keeps the evaluation bounded.
+pub fn my_channel_messages(ctx: &ViewContext) -> Vec<Message> {
+    joined_channel_ids(ctx, ctx.sender)
+        .flat_map(|channel_id| ctx.db.message().channel_and_seq().filter(channel_id))
+        .collect()
+}
+
+#[table(name = message_revision)]
+/// Previous versions of edited or deleted messages.
+pub struct MessageRevision {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64,
+    /// The text the message had before this revision replaced it.
+    text: String,
+    revised_by: Identity,
+    revised_at: Timestamp,
 }
 
 #[reducer]
 /// Clients invoke this reducer to set their user names.
 pub fn set_name(ctx: &ReducerContext, name: String) -> Result<(), String> {
+    check_not_banned(ctx, ctx.sender)?;
+    rename(ctx, ctx.sender, name)
+}
+
+/// Gives the user with `identity` a new name, once it's validated and claimed.
+fn rename(ctx: &ReducerContext, identity: Identity, name: String) -> Result<(), String> {
     let name = validate_name(name)?;
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
+    if let Some(user) = ctx.db.user().identity().find(identity) {
+        if user.name.as_deref() == Some(name.as_str()) {
+            return Ok(());
+        }
+        // Changing how the current name is written, e.g. its case, keeps the same claim,
+        // so it isn't recorded as a rename or held to the rename cooldown.
+        let restyled = user.name.as_deref().is_some_and(|current| normalize_name(current) == normalize_name(&name));
+        if !restyled {
+            claim_name(ctx, identity, user.name.clone(), &name)?;
+        }
         ctx.db.user().identity().update(User { name: Some(name), ..user });
         Ok(())
     } else {
@@ -30,62 +123,213 @@ pub fn set_name(ctx: &ReducerContext, name: String) -> Result<(), String> {
     }
 }
 
-/// Takes a name and checks if it's acceptable as a user's name.
+/// Takes a name and checks if it's acceptable as a user's name, trimming surrounding whitespace.
 fn validate_name(name: String) -> Result<String, String> {
+    let name = name.trim().to_string();
+    let length = name.chars().count();
     if name.is_empty() {
         Err("Names must not be empty".to_string())
+    } else if !(2..=32).contains(&length) {
+        Err("Names must be between 2 and 32 characters".to_string())
+    } else if !name.chars().all(|c| c.is_alphanumeric() || " -_.".contains(c)) {
+        Err("Names may only contain letters, digits, spaces, '-', '_' and '.'".to_string())
+    } else if name.contains("  ") {
+        Err("Names must not contain consecutive spaces".to_string())
     } else {
         Ok(name)
     }
 }
 
 #[reducer]
-/// Clients invoke this reducer to send messages.
-pub fn send_message(ctx: &ReducerContext, text: String) -> Result<(), String> {
-    let text = validate_message(text)?;
-    log::info!("{}", text);
-    ctx.db.message().insert(Message {
+/// Clients invoke this reducer to send messages to a channel they have joined,
+/// optionally as a reply in the thread of another message.
+pub fn send_message(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    text: String,
+    reply_to: Option<u64>,
+) -> Result<(), String> {
+    require_permission(ctx, SEND)?;
+    check_can_send(ctx, ctx.sender)?;
+    check_name_set(ctx)?;
+    let channel = find_channel(ctx, channel_id)?;
+    if !is_member(ctx, channel_id, ctx.sender) {
+        return Err(format!("Join #{} before sending messages to it", channel.name));
+    }
+    let reply_to = reply_to
+        .map(|parent_id| validate_reply_to(ctx, channel_id, parent_id))
+        .transpose()?;
+    let text = validate_message(ctx, text)?;
+    consume_token(ctx)?;
+    log::info!("#{}: {}", channel.name, text);
+    stop_typing(ctx, ctx.sender, channel_id);
+    let message = post(ctx, channel, text, reply_to);
+    // Senders have read everything up to their own message.
+    advance_marker(ctx, ctx.sender, channel_id, message.id);
+    Ok(())
+}
+
+/// Appends a message from the caller to `channel`, once it has been checked.
+fn post(ctx: &ReducerContext, channel: Channel, text: String, reply_to: Option<u64>) -> Message {
+    let channel_id = channel.id;
+    let seq = channel.last_seq + 1;
+    ctx.db.channel().id().update(Channel { last_seq: seq, ..channel });
+    let message = ctx.db.message().insert(Message {
         id: 0,
+        channel_id,
+        seq,
         sender: ctx.sender,
         text,
         sent: ctx.timestamp,
+        reply_to,
+        edited_at: None,
+        deleted: false,
     });
-    Ok(())
+    record_mentions(ctx, &message);
+    message
 }
 
-/// Takes a message's text and checks if it's acceptable to send.
-fn validate_message(text: String) -> Result<String, String> {
-    if text.is_empty() {
-        Err("Messages must not be empty".to_string())
+/// Checks that a reply targets an existing message in the same channel,
+/// returning the id of the thread's first message so threads never nest.
+fn validate_reply_to(ctx: &ReducerContext, channel_id: u64, parent_id: u64) -> Result<u64, String> {
+    let parent = ctx
+        .db
+        .message()
+        .id()
+        .find(parent_id)
+        .ok_or_else(|| format!("No message with id {}", parent_id))?;
+    if parent.channel_id != channel_id {
+        Err("Replies must be sent to the channel of the message they reply to".to_string())
+    } else if parent.deleted {
+        Err("Cannot reply to a deleted message".to_string())
     } else {
-        Ok(text)
+        Ok(parent.reply_to.unwrap_or(parent.id))
     }
 }
 
+#[reducer]
+/// Clients invoke this reducer to change the text of a message they sent.
+pub fn edit_message(ctx: &ReducerContext, id: u64, text: String) -> Result<(), String> {
+    check_can_send(ctx, ctx.sender)?;
+    let message = find_modifiable_message(ctx, id)?;
+    let text = validate_message(ctx, text)?;
+    consume_token(ctx)?;
+    record_revision(ctx, &message);
+    if message.sender != ctx.sender {
+        record(
+            ctx,
+            AuditAction::EditMessage,
+            Some(message.sender),
+            "",
+            format!("Message {} in channel {}", message.id, message.channel_id),
+        );
+    }
+    let message = ctx.db.message().id().update(Message {
+        text,
+        edited_at: Some(ctx.timestamp),
+        ..message
+    });
+    record_mentions(ctx, &message);
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to replace a message they sent with a tombstone.
+pub fn delete_message(ctx: &ReducerContext, id: u64) -> Result<(), String> {
+    let message = find_modifiable_message(ctx, id)?;
+    record_revision(ctx, &message);
+    record(
+        ctx,
+        AuditAction::DeleteMessage,
+        Some(message.sender),
+        "",
+        format!("Message {} in channel {}", message.id, message.channel_id),
+    );
+    clear_reactions(ctx, message.id);
+    clear_mentions(ctx, message.id);
+    ctx.db.message().id().update(Message {
+        text: String::new(),
+        deleted: true,
+        ..message
+    });
+    Ok(())
+}
+
+/// Finds a message the caller may edit or delete: one they sent,
+/// or any message if they hold the delete_any permission.
+fn find_modifiable_message(ctx: &ReducerContext, id: u64) -> Result<Message, String> {
+    let message = ctx
+        .db
+        .message()
+        .id()
+        .find(id)
+        .ok_or_else(|| format!("No message with id {}", id))?;
+    if message.deleted {
+        return Err("Message has been deleted".to_string());
+    }
+    if message.sender != ctx.sender && !has_permission(ctx, ctx.sender, DELETE_ANY) {
+        return Err("Only the sender or a moderator can modify this message".to_string());
+    }
+    Ok(message)
+}
+
+fn record_revision(ctx: &ReducerContext, message: &Message) {
+    ctx.db.message_revision().insert(MessageRevision {
+        id: 0,
+        message_id: message.id,
+        text: message.text.clone(),
+        revised_by: ctx.sender,
+        revised_at: ctx.timestamp,
+    });
+}
+
+#[reducer(init)]
+// Called when the module is first published
+pub fn init(ctx: &ReducerContext) {
+    roles::init_roles(ctx);
+    rate_limit::init_config(ctx);
+    validation::init_rules(ctx);
+    retention::init_schedule(ctx);
+    external_accounts::init_schedule(ctx);
+    default_channel(ctx);
+}
+
 #[reducer(client_connected)]
 // Called when a client connects to a SpacetimeDB database server
-pub fn client_connected(ctx: &ReducerContext) {
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
-        // If this is a returning user, i.e. we already have a `User` with this `Identity`,
-        // set `online: true`, but leave `name` and `identity` unchanged.
-        ctx.db.user().identity().update(User { online: true, ..user });
-    } else {
+// Banned identities are turned away by failing this reducer
+pub fn client_connected(ctx: &ReducerContext) -> Result<(), String> {
+    check_not_banned(ctx, ctx.sender)?;
+    // Services don't chat themselves, so they aren't made users.
+    if is_trusted_service(ctx, ctx.sender) && ctx.db.user().identity().find(ctx.sender).is_none() {
+        return Ok(());
+    }
+    // Returning users, i.e. ones we already have a `User` for, keep their row unchanged.
+    if ctx.db.user().identity().find(ctx.sender).is_none() {
         // If this is a new user, create a `User` row for the `Identity`,
-        // which is online, but hasn't set a name.
+        // which hasn't set a name, and place them in the default channel.
         ctx.db.user().insert(User {
             name: None,
             identity: ctx.sender,
-            online: true,
+            online: false,
+            last_seen: ctx.timestamp,
+            status: PresenceStatus::Online,
+            status_text: None,
         });
+        let general = default_channel(ctx);
+        add_member(ctx, general.id, ctx.sender);
     }
+    // The user is only marked online through their presence, which respects invisibility.
+    presence::connect(ctx);
+    Ok(())
 }
 
 #[reducer(client_disconnected)]
 // Called when a client disconnects from SpacetimeDB database server
 pub fn identity_disconnected(ctx: &ReducerContext) {
-    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
-        ctx.db.user().identity().update(User { online: false, ..user });
-    } else {
+    if ctx.db.user().identity().find(ctx.sender).is_some() {
+        presence::disconnect(ctx);
+        clear_typing(ctx, ctx.sender);
+    } else if !is_trusted_service(ctx, ctx.sender) {
         // This branch should be unreachable,
         // as it doesn't make sense for a client to disconnect without connecting first.
         log::warn!("Disconnect event for unknown user with identity {:?}", ctx.sender);
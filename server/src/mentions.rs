@@ -0,0 +1,117 @@
+use std::collections::BTreeSet;
+
+use spacetimedb::{table, view, Identity, ReducerContext, Table, Timestamp, ViewContext};
+
+use crate::channels::joined_channel_ids;
+use crate::names::find_by_name;
+use crate::{user, Message};
+
+/// Longest name that can be mentioned, the same as the longest allowed name.
+const MAX_NAME_CHARS: usize = 32;
+/// Users mentioned beyond this many in a single message aren't notified.
+const MAX_MENTIONS: usize = 10;
+
+#[table(name = mention)]
+/// A user mentioned with `@name` in a channel message, served by `my_mentions`.
+pub struct Mention {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[index(btree)]
+    pub message_id: u64,
+    pub channel_id: u64,
+    #[index(btree)]
+    pub mentioned: Identity,
+    pub sender: Identity,
+    pub at: Timestamp,
+}
+
+#[view(name = my_mentions, public)]
+/// Mentions of the calling identity in the channels it is still a member of.
+pub fn my_mentions(ctx: &ViewContext) -> Vec<Mention> {
+    ctx.db
+        .mention()
+        .mentioned()
+        .filter(ctx.sender)
+        .filter(|mention| {
+            joined_channel_ids(ctx, ctx.sender).any(|channel_id| channel_id == mention.channel_id)
+        })
+        .collect()
+}
+
+/// Brings the mentions of a message in line with its text after it was sent or edited,
+/// so users who were already mentioned aren't notified again.
+pub fn record_mentions(ctx: &ReducerContext, message: &Message) {
+    let mentioned = parse_mentions(ctx, &message.text, message.sender);
+    let existing: Vec<Mention> = ctx.db.mention().message_id().filter(message.id).collect();
+    for mention in &existing {
+        if !mentioned.contains(&mention.mentioned) {
+            ctx.db.mention().id().delete(mention.id);
+        }
+    }
+    for identity in mentioned {
+        if existing.iter().any(|mention| mention.mentioned == identity) {
+            continue;
+        }
+        ctx.db.mention().insert(Mention {
+            id: 0,
+            message_id: message.id,
+            channel_id: message.channel_id,
+            mentioned: identity,
+            sender: message.sender,
+            at: ctx.timestamp,
+        });
+    }
+}
+
+/// Removes every mention of a message, such as when it's deleted or archived.
+pub fn clear_mentions(ctx: &ReducerContext, message_id: u64) {
+    ctx.db.mention().message_id().delete(message_id);
+}
+
+/// Finds the users named after each `@` in `text`, other than its sender.
+/// Names may contain spaces, so the longest name that matches is taken.
+fn parse_mentions(ctx: &ReducerContext, text: &str, sender: Identity) -> BTreeSet<Identity> {
+    let mut mentioned = BTreeSet::new();
+    let mut previous = None;
+    for (index, c) in text.char_indices() {
+        // An `@` inside a word, like in an email address, isn't a mention.
+        let starts_mention = c == '@' && !previous.is_some_and(char::is_alphanumeric);
+        previous = Some(c);
+        if !starts_mention {
+            continue;
+        }
+        let identity = name_candidates(&text[index + 1..])
+            .into_iter()
+            .rev()
+            .filter_map(|candidate| find_by_name(ctx, candidate))
+            .find(|identity| ctx.db.user().identity().find(identity).is_some());
+        if let Some(identity) = identity.filter(|identity| *identity != sender) {
+            mentioned.insert(identity);
+            if mentioned.len() == MAX_MENTIONS {
+                break;
+            }
+        }
+    }
+    mentioned
+}
+
+/// Every prefix of `text` that could be a whole name, shortest first.
+fn name_candidates(text: &str) -> Vec<&str> {
+    let mut candidates = Vec::new();
+    for (index, c) in text.char_indices().take(MAX_NAME_CHARS) {
+        if !(c.is_alphanumeric() || " -_.".contains(c)) {
+            break;
+        }
+        let end = index + c.len_utf8();
+        // Names end where a word does, so `@bob's` mentions bob but `@bobby` doesn't.
+        let at_boundary = text[end..]
+            .chars()
+            .next()
+            .is_none_or(|next| !next.is_alphanumeric());
+        if at_boundary && c != ' ' {
+            candidates.push(&text[..end]);
+        }
+    }
+    candidates
+}
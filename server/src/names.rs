@@ -0,0 +1,163 @@
+use spacetimedb::{table, Identity, ReducerContext, Table, TimeDuration, Timestamp};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+use crate::audit::{record, AuditAction};
+
+/// Names nobody may take, since they could pass for staff or the module itself.
+/// Compared in normalized form, so look-alikes such as "Adm1n" or "ADMIN" are caught too.
+const RESERVED_NAMES: [&str; 12] = [
+    "admin",
+    "administrator",
+    "moderator",
+    "mod",
+    "owner",
+    "staff",
+    "support",
+    "system",
+    "server",
+    "root",
+    "everyone",
+    "here",
+];
+
+/// How long users have to wait after a rename before they can rename again.
+const RENAME_COOLDOWN_MICROS: i64 = 10 * 60 * 1_000_000;
+
+#[table(name = name_claim)]
+/// The normalized form of every user's current name, keeping names unique
+/// even when they only differ in case or in look-alike characters.
+pub struct NameClaim {
+    #[primary_key]
+    identity: Identity,
+    #[unique]
+    normalized: String,
+}
+
+#[table(name = name_history, public)]
+/// Every name a user has gone by, so impersonation can be traced.
+pub struct NameChange {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[index(btree)]
+    pub identity: Identity,
+    pub old_name: Option<String>,
+    pub new_name: String,
+    pub changed_at: Timestamp,
+}
+
+/// The identity currently going by `name` or a name that looks like it.
+pub fn find_by_name(ctx: &ReducerContext, name: &str) -> Option<Identity> {
+    ctx.db
+        .name_claim()
+        .normalized()
+        .find(normalize_name(name))
+        .map(|claim| claim.identity)
+}
+
+/// Reduces a name to a form that is equal for all names that look alike:
+/// compatibility-normalized, mapped to its confusable skeleton and case-folded.
+/// Skeletons keep case, such as "0" becoming "O", so the case-folded form is mapped again.
+/// They also map "I" to "l" but keep "i", which is folded into "l" too.
+pub fn normalize_name(name: &str) -> String {
+    let compatible: String = name.trim().nfkc().collect();
+    let folded: String = skeleton(&compatible).flat_map(char::to_lowercase).collect();
+    skeleton(&folded)
+        .map(|c| if c == 'i' { 'l' } else { c })
+        .collect()
+}
+
+/// Claims `name` for `identity`, who currently goes by `old_name`,
+/// and records the rename in the `name_history`.
+pub fn claim_name(
+    ctx: &ReducerContext,
+    identity: Identity,
+    old_name: Option<String>,
+    name: &str,
+) -> Result<(), String> {
+    let normalized = normalize_name(name);
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| normalize_name(reserved) == normalized)
+    {
+        return Err(format!("The name {} is reserved", name));
+    }
+    if let Some(claim) = ctx.db.name_claim().normalized().find(&normalized) {
+        if claim.identity != identity {
+            return Err(format!("The name {} is already taken", name));
+        }
+    }
+    if let Some(last_change) = last_name_change(ctx, identity) {
+        let allowed_at = last_change.changed_at + TimeDuration::from_micros(RENAME_COOLDOWN_MICROS);
+        let wait = allowed_at
+            .time_duration_since(ctx.timestamp)
+            .filter(|wait| wait.to_micros() > 0);
+        if let Some(wait) = wait {
+            let minutes = (wait.to_micros() + 59_999_999) / 60_000_000;
+            return Err(format!(
+                "You can change your name again in {} minutes",
+                minutes
+            ));
+        }
+    }
+    ctx.db.name_claim().identity().delete(identity);
+    ctx.db.name_claim().insert(NameClaim {
+        identity,
+        normalized,
+    });
+    record(
+        ctx,
+        AuditAction::Rename,
+        Some(identity),
+        "",
+        format!("{} -> {}", old_name.as_deref().unwrap_or("(no name)"), name),
+    );
+    ctx.db.name_history().insert(NameChange {
+        id: 0,
+        identity,
+        old_name,
+        new_name: name.to_string(),
+        changed_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+fn last_name_change(ctx: &ReducerContext, identity: Identity) -> Option<NameChange> {
+    ctx.db
+        .name_history()
+        .identity()
+        .filter(identity)
+        .max_by_key(|change| change.changed_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_name;
+
+    #[test]
+    fn digits_that_look_like_letters_are_caught() {
+        assert_eq!(normalize_name("b0b"), normalize_name("bob"));
+        assert_eq!(normalize_name("Adm1n"), normalize_name("admin"));
+        assert_eq!(normalize_name("R00T"), normalize_name("root"));
+    }
+
+    #[test]
+    fn case_is_ignored() {
+        assert_eq!(normalize_name("ADMIN"), normalize_name("admin"));
+        assert_eq!(normalize_name("AdmIn"), normalize_name("admin"));
+        assert_eq!(normalize_name("ALICE"), normalize_name("alice"));
+    }
+
+    #[test]
+    fn look_alike_scripts_and_widths_are_caught() {
+        assert_eq!(normalize_name("b\u{03BF}b"), normalize_name("bob"));
+        assert_eq!(normalize_name("\u{FF22}ob"), normalize_name("bob"));
+    }
+
+    #[test]
+    fn different_names_stay_apart() {
+        assert_ne!(normalize_name("bob"), normalize_name("rob"));
+        assert_ne!(normalize_name("alice"), normalize_name("alicia"));
+    }
+}
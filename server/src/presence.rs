@@ -0,0 +1,140 @@
+use spacetimedb::{
+    reducer, table, view, Identity, ReducerContext, SpacetimeType, Table, ViewContext,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::sanctions::check_not_banned;
+use crate::validation::validate_message;
+use crate::{user, User};
+
+/// Longest status text, in user-perceived characters.
+const MAX_STATUS_TEXT: usize = 64;
+
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    DoNotDisturb,
+    /// Connected, but shown to others as offline.
+    Invisible,
+}
+
+#[table(name = presence)]
+/// How many connections each identity has open and the status it picked.
+/// Kept out of the public `user` table so invisible users can't be told apart from offline ones.
+pub struct Presence {
+    #[primary_key]
+    pub identity: Identity,
+    /// The identity is online for as long as any of its connections are.
+    pub connections: u32,
+    pub status: PresenceStatus,
+}
+
+#[view(name = my_presence, public)]
+/// The calling identity's own presence, including whether it's invisible.
+pub fn my_presence(ctx: &ViewContext) -> Option<Presence> {
+    ctx.db.presence().identity().find(ctx.sender)
+}
+
+#[reducer]
+/// Clients invoke this reducer to change how others see them, optionally with a custom status text.
+pub fn set_status(
+    ctx: &ReducerContext,
+    status: PresenceStatus,
+    text: Option<String>,
+) -> Result<(), String> {
+    check_not_banned(ctx, ctx.sender)?;
+    let text = text
+        .filter(|text| !text.trim().is_empty())
+        .map(|text| validate_status_text(ctx, text))
+        .transpose()?;
+    let presence = find_presence(ctx, ctx.sender);
+    ctx.db
+        .presence()
+        .identity()
+        .update(Presence { status, ..presence });
+    if let Some(user) = ctx.db.user().identity().find(ctx.sender) {
+        ctx.db.user().identity().update(User {
+            status_text: text,
+            ..user
+        });
+    }
+    publish(ctx, ctx.sender);
+    Ok(())
+}
+
+/// Counts a new connection of the caller.
+pub fn connect(ctx: &ReducerContext) {
+    let presence = find_presence(ctx, ctx.sender);
+    ctx.db.presence().identity().update(Presence {
+        connections: presence.connections + 1,
+        ..presence
+    });
+    publish(ctx, ctx.sender);
+}
+
+/// Counts a closed connection of the caller, who goes offline once none are left.
+pub fn disconnect(ctx: &ReducerContext) {
+    let presence = find_presence(ctx, ctx.sender);
+    ctx.db.presence().identity().update(Presence {
+        connections: presence.connections.saturating_sub(1),
+        ..presence
+    });
+    publish(ctx, ctx.sender);
+}
+
+/// Finds the presence of `identity`, creating it for identities that never connected before.
+fn find_presence(ctx: &ReducerContext, identity: Identity) -> Presence {
+    ctx.db
+        .presence()
+        .identity()
+        .find(identity)
+        .unwrap_or_else(|| {
+            ctx.db.presence().insert(Presence {
+                identity,
+                connections: 0,
+                status: PresenceStatus::Online,
+            })
+        })
+}
+
+/// Copies what others may see of a user's presence to their public `user` row.
+/// Invisible users appear offline, and their last seen time stops advancing.
+fn publish(ctx: &ReducerContext, identity: Identity) {
+    let Some(user) = ctx.db.user().identity().find(identity) else {
+        return;
+    };
+    let presence = find_presence(ctx, identity);
+    let visible = presence.status != PresenceStatus::Invisible;
+    let online = visible && presence.connections > 0;
+    ctx.db.user().identity().update(User {
+        online,
+        status: if visible {
+            presence.status
+        } else {
+            PresenceStatus::Online
+        },
+        // Offline users were last seen when they went offline.
+        last_seen: if visible || user.online {
+            ctx.timestamp
+        } else {
+            user.last_seen
+        },
+        ..user
+    });
+}
+
+/// Takes a status text and checks it like a message, but with a much shorter limit.
+fn validate_status_text(ctx: &ReducerContext, text: String) -> Result<String, String> {
+    let text = validate_message(ctx, text)?;
+    if text.contains('\n') {
+        Err("Status texts must be a single line".to_string())
+    } else if text.graphemes(true).count() > MAX_STATUS_TEXT {
+        Err(format!(
+            "Status texts must be at most {} characters",
+            MAX_STATUS_TEXT
+        ))
+    } else {
+        Ok(text)
+    }
+}
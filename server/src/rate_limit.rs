@@ -0,0 +1,220 @@
+use spacetimedb::{reducer, table, Identity, ReducerContext, Table, TimeDuration, Timestamp};
+
+use crate::audit::{record, AuditAction};
+use crate::roles::{require_permission, MANAGE_SETTINGS};
+
+const CONFIG_ID: u8 = 0;
+
+#[table(name = rate_limit_config, public)]
+/// Module-wide flood control settings, stored as a single row.
+pub struct RateLimitConfig {
+    #[primary_key]
+    id: u8,
+    /// How many messages can be sent back to back.
+    pub burst: u32,
+    /// How long it takes to regain the ability to send one more message.
+    pub refill_interval: TimeDuration,
+    /// How often the bucket may run dry within `strike_window` before a mute.
+    pub strikes_before_mute: u32,
+    pub strike_window: TimeDuration,
+    /// Length of the first mute, doubled for every further mute within `strike_window` of the last.
+    pub base_mute: TimeDuration,
+    pub max_mute: TimeDuration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            id: CONFIG_ID,
+            burst: 5,
+            refill_interval: TimeDuration::from_micros(2_000_000),
+            strikes_before_mute: 3,
+            strike_window: TimeDuration::from_micros(60_000_000),
+            base_mute: TimeDuration::from_micros(30_000_000),
+            max_mute: TimeDuration::from_micros(3_600_000_000),
+        }
+    }
+}
+
+#[table(name = rate_limit_bucket)]
+/// Per-identity token bucket and mute escalation state.
+pub struct RateLimitBucket {
+    #[primary_key]
+    identity: Identity,
+    tokens: u32,
+    refilled_at: Timestamp,
+    strikes: u32,
+    last_strike: Option<Timestamp>,
+    /// Number of mutes in a row, used to escalate their length.
+    mutes: u32,
+    muted_until: Option<Timestamp>,
+}
+
+#[reducer]
+/// Settings managers invoke this reducer to change the flood control settings.
+pub fn configure_rate_limit(
+    ctx: &ReducerContext,
+    burst: u32,
+    refill_interval: TimeDuration,
+    strikes_before_mute: u32,
+    strike_window: TimeDuration,
+    base_mute: TimeDuration,
+    max_mute: TimeDuration,
+) -> Result<(), String> {
+    require_permission(ctx, MANAGE_SETTINGS)?;
+    if burst == 0 || strikes_before_mute == 0 {
+        return Err("Burst and strikes before mute must be at least 1".to_string());
+    }
+    let durations = [refill_interval, strike_window, base_mute, max_mute];
+    if durations.iter().any(|duration| duration.to_micros() <= 0) {
+        return Err("Durations must be positive".to_string());
+    }
+    let config = RateLimitConfig {
+        id: CONFIG_ID,
+        burst,
+        refill_interval,
+        strikes_before_mute,
+        strike_window,
+        base_mute,
+        max_mute,
+    };
+    record(
+        ctx,
+        AuditAction::ChangeSettings,
+        None,
+        "",
+        format!(
+            "Rate limit: burst {}, refill every {}, mute after {} strikes",
+            burst, refill_interval, strikes_before_mute
+        ),
+    );
+    if ctx.db.rate_limit_config().id().find(CONFIG_ID).is_some() {
+        ctx.db.rate_limit_config().id().update(config);
+    } else {
+        ctx.db.rate_limit_config().insert(config);
+    }
+    Ok(())
+}
+
+pub fn init_config(ctx: &ReducerContext) {
+    if ctx.db.rate_limit_config().id().find(CONFIG_ID).is_none() {
+        ctx.db
+            .rate_limit_config()
+            .insert(RateLimitConfig::default());
+    }
+}
+
+/// Takes one message's worth of tokens from the sender's bucket,
+/// failing while they are muted or sending too fast.
+///
+/// A failing reducer is rolled back, so rejected sends can't be counted.
+/// Instead, a strike is recorded whenever a send empties the bucket,
+/// which a flooding client does over and over.
+pub fn consume_token(ctx: &ReducerContext) -> Result<(), String> {
+    let config = ctx
+        .db
+        .rate_limit_config()
+        .id()
+        .find(CONFIG_ID)
+        .unwrap_or_default();
+    let now = ctx.timestamp;
+    let mut bucket = ctx
+        .db
+        .rate_limit_bucket()
+        .identity()
+        .find(ctx.sender)
+        .unwrap_or(RateLimitBucket {
+            identity: ctx.sender,
+            tokens: config.burst,
+            refilled_at: now,
+            strikes: 0,
+            last_strike: None,
+            mutes: 0,
+            muted_until: None,
+        });
+    if let Some(muted_until) = bucket.muted_until.filter(|until| *until > now) {
+        return Err(format!(
+            "You are muted for another {} seconds",
+            seconds_until(now, muted_until)
+        ));
+    }
+
+    let interval = config.refill_interval.to_micros().max(1);
+    let elapsed = now
+        .time_duration_since(bucket.refilled_at)
+        .map_or(0, |elapsed| elapsed.to_micros());
+    let refills = elapsed / interval;
+    bucket.tokens = bucket
+        .tokens
+        .saturating_add(u32::try_from(refills).unwrap_or(u32::MAX))
+        .min(config.burst);
+    bucket.refilled_at = if bucket.tokens == config.burst {
+        now
+    } else {
+        bucket.refilled_at + TimeDuration::from_micros(refills * interval)
+    };
+    if bucket.tokens == 0 {
+        let next_token = bucket.refilled_at + config.refill_interval;
+        return Err(format!(
+            "You are sending messages too fast, try again in {} seconds",
+            seconds_until(now, next_token)
+        ));
+    }
+
+    bucket.tokens -= 1;
+    if bucket.tokens == 0 {
+        strike(&config, &mut bucket, now);
+    }
+    if ctx
+        .db
+        .rate_limit_bucket()
+        .identity()
+        .find(ctx.sender)
+        .is_some()
+    {
+        ctx.db.rate_limit_bucket().identity().update(bucket);
+    } else {
+        ctx.db.rate_limit_bucket().insert(bucket);
+    }
+    Ok(())
+}
+
+/// Records that a bucket ran dry, muting its owner after too many strikes in a row.
+fn strike(config: &RateLimitConfig, bucket: &mut RateLimitBucket, now: Timestamp) {
+    let window = config.strike_window.to_micros();
+    let is_recent = |time: Timestamp| {
+        now.time_duration_since(time)
+            .is_some_and(|since| since.to_micros() < window)
+    };
+    if !bucket.last_strike.is_some_and(is_recent) {
+        bucket.strikes = 0;
+    }
+    if !bucket.muted_until.is_some_and(is_recent) {
+        bucket.mutes = 0;
+    }
+    bucket.strikes += 1;
+    bucket.last_strike = Some(now);
+    if bucket.strikes < config.strikes_before_mute {
+        return;
+    }
+    let mute = config
+        .base_mute
+        .to_micros()
+        .saturating_mul(2i64.saturating_pow(bucket.mutes))
+        .min(config.max_mute.to_micros());
+    bucket.muted_until = Some(now + TimeDuration::from_micros(mute));
+    bucket.mutes += 1;
+    bucket.strikes = 0;
+    log::info!(
+        "Muted {} for {} seconds for flooding",
+        bucket.identity,
+        mute / 1_000_000
+    );
+}
+
+fn seconds_until(now: Timestamp, later: Timestamp) -> i64 {
+    let micros = later
+        .time_duration_since(now)
+        .map_or(0, |until| until.to_micros());
+    (micros + 999_999) / 1_000_000
+}
@@ -0,0 +1,118 @@
+use spacetimedb::{reducer, table, view, Identity, ReducerContext, Table, Timestamp, ViewContext};
+use unicode_properties::UnicodeEmoji;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::channels::{is_member, joined_channel_ids};
+use crate::message;
+use crate::sanctions::check_can_send;
+
+/// Reactions to channel messages, served by `my_channel_reactions`.
+#[table(
+    name = reaction,
+    index(name = message_reactor_emoji, btree(columns = [message_id, reactor, emoji])),
+    index(name = channel_and_seq, btree(columns = [channel_id, seq]))
+)]
+pub struct Reaction {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    pub message_id: u64,
+    pub channel_id: u64,
+    /// Position of the message in its channel, so clients can subscribe to
+    /// the reactions of the same page of history as its messages.
+    pub seq: u64,
+    pub reactor: Identity,
+    pub emoji: String,
+    pub reacted_at: Timestamp,
+}
+
+#[view(name = my_channel_reactions, public)]
+/// Every reaction to the messages of the channels the calling identity is a member of.
+pub fn my_channel_reactions(ctx: &ViewContext) -> Vec<Reaction> {
+    joined_channel_ids(ctx, ctx.sender)
+        .flat_map(|channel_id| ctx.db.reaction().channel_and_seq().filter(channel_id))
+        .collect()
+}
+
+#[reducer]
+/// Clients invoke this reducer to react to a message in a channel they have joined.
+pub fn add_reaction(ctx: &ReducerContext, message_id: u64, emoji: String) -> Result<(), String> {
+    check_can_send(ctx, ctx.sender)?;
+    let emoji = validate_emoji(emoji)?;
+    let message = ctx
+        .db
+        .message()
+        .id()
+        .find(message_id)
+        .ok_or_else(|| format!("No message with id {}", message_id))?;
+    if message.deleted {
+        return Err("Cannot react to a deleted message".to_string());
+    }
+    if !is_member(ctx, message.channel_id, ctx.sender) {
+        return Err("Join the channel before reacting to its messages".to_string());
+    }
+    if has_reacted(ctx, message_id, ctx.sender, &emoji) {
+        return Err(format!("Already reacted with {}", emoji));
+    }
+    ctx.db.reaction().insert(Reaction {
+        id: 0,
+        message_id,
+        channel_id: message.channel_id,
+        seq: message.seq,
+        reactor: ctx.sender,
+        emoji,
+        reacted_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Clients invoke this reducer to take back one of their reactions.
+pub fn remove_reaction(ctx: &ReducerContext, message_id: u64, emoji: String) -> Result<(), String> {
+    let emoji = emoji.trim();
+    let removed = ctx
+        .db
+        .reaction()
+        .message_reactor_emoji()
+        .delete((message_id, ctx.sender, emoji));
+    if removed == 0 {
+        Err(format!("No {} reaction to remove", emoji))
+    } else {
+        Ok(())
+    }
+}
+
+/// Removes every reaction to a message, e.g. once it has been deleted.
+pub fn clear_reactions(ctx: &ReducerContext, message_id: u64) {
+    ctx.db.reaction().message_reactor_emoji().delete(message_id);
+}
+
+fn has_reacted(ctx: &ReducerContext, message_id: u64, reactor: Identity, emoji: &str) -> bool {
+    ctx.db
+        .reaction()
+        .message_reactor_emoji()
+        .filter((message_id, reactor, emoji))
+        .next()
+        .is_some()
+}
+
+/// Whether a single grapheme is an emoji, judged by the code point it starts with.
+/// Digits, '#' and '*' count as emoji too, but only as keycaps ending in U+20E3.
+fn is_emoji(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(|first| {
+        first.is_emoji_char() && (!first.is_ascii() || grapheme.ends_with('\u{20E3}'))
+    })
+}
+
+/// Takes an emoji and checks if it's acceptable as a reaction.
+fn validate_emoji(emoji: String) -> Result<String, String> {
+    let emoji = emoji.trim().to_string();
+    if emoji.is_empty() {
+        Err("Reactions must not be empty".to_string())
+    } else if emoji.graphemes(true).count() > 1 || !is_emoji(&emoji) {
+        // A single grapheme can still be made of several code points, like flags or skin tones.
+        Err("Reactions must be a single emoji".to_string())
+    } else {
+        Ok(emoji)
+    }
+}
@@ -0,0 +1,85 @@
+use spacetimedb::{reducer, table, view, Identity, ReducerContext, Table, ViewContext};
+
+use crate::channels::{find_channel, is_member};
+use crate::message;
+
+/// How far each user has read in each channel, served by `my_read_markers`.
+#[table(
+    name = read_marker,
+    index(name = identity_and_channel, btree(columns = [identity, channel_id]))
+)]
+pub struct ReadMarker {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    pub identity: Identity,
+    pub channel_id: u64,
+    /// Every message of the channel up to and including this one has been read.
+    pub last_read_message_id: u64,
+}
+
+#[view(name = my_read_markers, public)]
+/// The calling identity's read markers in every channel.
+pub fn my_read_markers(ctx: &ViewContext) -> Vec<ReadMarker> {
+    ctx.db
+        .read_marker()
+        .identity_and_channel()
+        .filter(ctx.sender)
+        .collect()
+}
+
+#[reducer]
+/// Clients invoke this reducer once the user has seen a channel up to a message.
+pub fn mark_read(ctx: &ReducerContext, channel_id: u64, message_id: u64) -> Result<(), String> {
+    let channel = find_channel(ctx, channel_id)?;
+    if !is_member(ctx, channel_id, ctx.sender) {
+        return Err(format!("Not a member of #{}", channel.name));
+    }
+    match ctx.db.message().id().find(message_id) {
+        Some(message) if message.channel_id == channel_id => {}
+        _ => {
+            return Err(format!(
+                "No message with id {} in #{}",
+                message_id, channel.name
+            ))
+        }
+    }
+    advance_marker(ctx, ctx.sender, channel_id, message_id);
+    Ok(())
+}
+
+/// Moves the read marker of `identity` in a channel forward to `message_id`,
+/// leaving it in place if it's already further along.
+pub fn advance_marker(ctx: &ReducerContext, identity: Identity, channel_id: u64, message_id: u64) {
+    let marker = ctx
+        .db
+        .read_marker()
+        .identity_and_channel()
+        .filter((identity, channel_id))
+        .next();
+    match marker {
+        Some(marker) if marker.last_read_message_id >= message_id => {}
+        Some(marker) => {
+            ctx.db.read_marker().id().update(ReadMarker {
+                last_read_message_id: message_id,
+                ..marker
+            });
+        }
+        None => {
+            ctx.db.read_marker().insert(ReadMarker {
+                id: 0,
+                identity,
+                channel_id,
+                last_read_message_id: message_id,
+            });
+        }
+    }
+}
+
+/// Removes the read marker of `identity` in a channel, such as when it leaves the channel.
+pub fn clear_marker(ctx: &ReducerContext, identity: Identity, channel_id: u64) {
+    ctx.db
+        .read_marker()
+        .identity_and_channel()
+        .delete((identity, channel_id));
+}
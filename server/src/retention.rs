@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+
+use spacetimedb::{
+    reducer, table, Identity, ReducerContext, ScheduleAt, Table, TimeDuration, Timestamp,
+};
+
+use crate::audit::{record, AuditAction};
+use crate::channels::{channel, find_channel};
+use crate::mentions::clear_mentions;
+use crate::reactions::clear_reactions;
+use crate::roles::{require_permission, MANAGE_CHANNELS};
+use crate::{message, Message};
+
+/// How long messages are kept in channels without a retention policy: 30 days.
+const DEFAULT_MAX_AGE_MICROS: i64 = 30 * 24 * 60 * 60 * 1_000_000;
+/// How many messages are kept in channels without a retention policy.
+const DEFAULT_MAX_MESSAGES: u32 = 1000;
+/// How often channels are pruned: every hour.
+const PRUNE_INTERVAL_MICROS: i64 = 60 * 60 * 1_000_000;
+
+#[table(name = retention_policy, public)]
+/// How long and how many messages a channel keeps before they are archived.
+pub struct RetentionPolicy {
+    #[primary_key]
+    pub channel_id: u64,
+    /// Messages older than this are archived, or kept forever if `None`.
+    pub max_age: Option<TimeDuration>,
+    /// The oldest messages beyond this count are archived, or never if `None`.
+    pub max_messages: Option<u32>,
+}
+
+#[table(name = archived_message)]
+/// Messages pruned from their channel, kept out of client subscriptions.
+pub struct ArchivedMessage {
+    #[primary_key]
+    id: u64,
+    #[index(btree)]
+    channel_id: u64,
+    seq: u64,
+    sender: Identity,
+    sent: Timestamp,
+    text: String,
+    reply_to: Option<u64>,
+    edited_at: Option<Timestamp>,
+    deleted: bool,
+    archived_at: Timestamp,
+}
+
+#[table(name = prune_schedule, scheduled(prune_messages))]
+pub struct PruneSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+#[reducer]
+/// Channel managers invoke this reducer to change how long a channel keeps its messages.
+pub fn set_retention_policy(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    max_age: Option<TimeDuration>,
+    max_messages: Option<u32>,
+) -> Result<(), String> {
+    require_permission(ctx, MANAGE_CHANNELS)?;
+    let channel = find_channel(ctx, channel_id)?;
+    if max_age.is_some_and(|max_age| max_age.to_micros() <= 0) {
+        return Err("Retention periods must be positive".to_string());
+    }
+    if max_messages == Some(0) {
+        return Err("Channels must keep at least 1 message".to_string());
+    }
+    record(
+        ctx,
+        AuditAction::ChangeRetention,
+        None,
+        "",
+        format!(
+            "#{}: max age {:?}, max messages {:?}",
+            channel.name, max_age, max_messages
+        ),
+    );
+    let policy = RetentionPolicy {
+        channel_id,
+        max_age,
+        max_messages,
+    };
+    if ctx
+        .db
+        .retention_policy()
+        .channel_id()
+        .find(channel_id)
+        .is_some()
+    {
+        ctx.db.retention_policy().channel_id().update(policy);
+    } else {
+        ctx.db.retention_policy().insert(policy);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Called by the `prune_schedule` to archive messages that fall outside their channel's policy.
+pub fn prune_messages(ctx: &ReducerContext, _schedule: PruneSchedule) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Only the module itself can prune messages".to_string());
+    }
+    let mut archived = 0;
+    for channel in ctx.db.channel().iter() {
+        for message in expired_messages(ctx, channel.id) {
+            archive(ctx, message);
+            archived += 1;
+        }
+    }
+    if archived > 0 {
+        log::info!("Archived {} messages", archived);
+    }
+    Ok(())
+}
+
+/// Schedules pruning to run periodically.
+pub fn init_schedule(ctx: &ReducerContext) {
+    ctx.db.prune_schedule().insert(PruneSchedule {
+        scheduled_id: 0,
+        scheduled_at: TimeDuration::from_micros(PRUNE_INTERVAL_MICROS).into(),
+    });
+}
+
+/// Messages of a channel that are too old or beyond its message cap,
+/// along with the replies of any thread whose first message is among them.
+fn expired_messages(ctx: &ReducerContext, channel_id: u64) -> Vec<Message> {
+    let policy = ctx
+        .db
+        .retention_policy()
+        .channel_id()
+        .find(channel_id)
+        .unwrap_or(RetentionPolicy {
+            channel_id,
+            max_age: Some(TimeDuration::from_micros(DEFAULT_MAX_AGE_MICROS)),
+            max_messages: Some(DEFAULT_MAX_MESSAGES),
+        });
+    let mut messages: Vec<Message> = ctx
+        .db
+        .message()
+        .channel_and_seq()
+        .filter(channel_id)
+        .collect();
+    messages.sort_by_key(|message| message.seq);
+    let excess = policy
+        .max_messages
+        .map_or(0, |max| messages.len().saturating_sub(max as usize));
+    let cutoff = policy
+        .max_age
+        .and_then(|max_age| ctx.timestamp.checked_sub(max_age));
+    let expired: HashSet<u64> = messages
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| {
+            *index < excess || cutoff.is_some_and(|cutoff| message.sent < cutoff)
+        })
+        .map(|(_, message)| message.id)
+        .collect();
+    messages
+        .into_iter()
+        .filter(|message| {
+            expired.contains(&message.id)
+                || message
+                    .reply_to
+                    .is_some_and(|parent| expired.contains(&parent))
+        })
+        .collect()
+}
+
+fn archive(ctx: &ReducerContext, message: Message) {
+    clear_reactions(ctx, message.id);
+    clear_mentions(ctx, message.id);
+    ctx.db.message().id().delete(message.id);
+    ctx.db.archived_message().insert(ArchivedMessage {
+        id: message.id,
+        channel_id: message.channel_id,
+        seq: message.seq,
+        sender: message.sender,
+        sent: message.sent,
+        text: message.text,
+        reply_to: message.reply_to,
+        edited_at: message.edited_at,
+        deleted: message.deleted,
+        archived_at: ctx.timestamp,
+    });
+}
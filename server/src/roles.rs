@@ -0,0 +1,342 @@
+use spacetimedb::{reducer, table, Identity, ReducerContext, Table, ViewContext};
+
+use crate::audit::{record, AuditAction};
+
+/// Sending messages to channels and other users.
+pub const SEND: u32 = 1 << 0;
+/// Editing and deleting messages sent by others.
+pub const DELETE_ANY: u32 = 1 << 1;
+/// Temporarily silencing users.
+pub const MUTE: u32 = 1 << 2;
+/// Banning users from the module.
+pub const BAN: u32 = 1 << 3;
+/// Joining and inviting others to any channel, including private ones.
+pub const MANAGE_CHANNELS: u32 = 1 << 4;
+/// Creating, changing, granting and revoking roles.
+pub const MANAGE_ROLES: u32 = 1 << 5;
+/// Changing module-wide settings such as rate limits and message rules.
+pub const MANAGE_SETTINGS: u32 = 1 << 6;
+/// Creating new channels, which their creator joins right away.
+pub const CREATE_CHANNELS: u32 = 1 << 7;
+pub const ALL_PERMISSIONS: u32 = SEND
+    | DELETE_ANY
+    | MUTE
+    | BAN
+    | MANAGE_CHANNELS
+    | MANAGE_ROLES
+    | MANAGE_SETTINGS
+    | CREATE_CHANNELS;
+
+/// Permissions only role managers can hand out, never trusted services syncing roles.
+const UNSYNCABLE_PERMISSIONS: u32 = MANAGE_ROLES | MANAGE_SETTINGS;
+
+/// Role held by the identity that published the module.
+pub const OWNER_ROLE: &str = "owner";
+/// Role whose permissions everyone has, without it being granted.
+pub const MEMBER_ROLE: &str = "member";
+
+#[table(name = role, public)]
+pub struct Role {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[unique]
+    pub name: String,
+    /// Bitset of the permission constants in this module.
+    pub permissions: u32,
+}
+
+#[table(
+    name = user_role,
+    public,
+    index(name = identity_and_role, btree(columns = [identity, role_id]))
+)]
+pub struct UserRole {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    pub identity: Identity,
+    #[index(btree)]
+    pub role_id: u64,
+}
+
+#[reducer]
+/// Role managers invoke this reducer to create a role they can then grant.
+pub fn create_role(ctx: &ReducerContext, name: String, permissions: u32) -> Result<(), String> {
+    require_permission(ctx, MANAGE_ROLES)?;
+    let name = validate_role_name(name)?;
+    check_permission_bits(ctx, permissions)?;
+    if ctx.db.role().name().find(&name).is_some() {
+        return Err(format!("Role {} already exists", name));
+    }
+    record(
+        ctx,
+        AuditAction::CreateRole,
+        None,
+        "",
+        format!("{} with permissions {:#x}", name, permissions),
+    );
+    ctx.db.role().insert(Role {
+        id: 0,
+        name,
+        permissions,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Role managers invoke this reducer to change what a role allows.
+pub fn set_role_permissions(
+    ctx: &ReducerContext,
+    role_id: u64,
+    permissions: u32,
+) -> Result<(), String> {
+    require_permission(ctx, MANAGE_ROLES)?;
+    let role = find_role(ctx, role_id)?;
+    if role.name == OWNER_ROLE {
+        return Err("The owner role always has every permission".to_string());
+    }
+    check_permission_bits(ctx, role.permissions | permissions)?;
+    record(
+        ctx,
+        AuditAction::ChangeRole,
+        None,
+        "",
+        format!(
+            "{} from {:#x} to {:#x}",
+            role.name, role.permissions, permissions
+        ),
+    );
+    ctx.db.role().id().update(Role {
+        permissions,
+        ..role
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Role managers invoke this reducer to delete a role, revoking it from everyone.
+pub fn delete_role(ctx: &ReducerContext, role_id: u64) -> Result<(), String> {
+    require_permission(ctx, MANAGE_ROLES)?;
+    let role = find_role(ctx, role_id)?;
+    if role.name == OWNER_ROLE || role.name == MEMBER_ROLE {
+        return Err(format!("The {} role cannot be deleted", role.name));
+    }
+    check_permission_bits(ctx, role.permissions)?;
+    record(ctx, AuditAction::DeleteRole, None, "", role.name);
+    ctx.db.user_role().role_id().delete(role_id);
+    ctx.db.role().id().delete(role_id);
+    Ok(())
+}
+
+#[reducer]
+/// Role managers invoke this reducer to give a user a role.
+pub fn grant_role(ctx: &ReducerContext, identity: Identity, role_id: u64) -> Result<(), String> {
+    require_permission(ctx, MANAGE_ROLES)?;
+    let role = find_role(ctx, role_id)?;
+    check_permission_bits(ctx, role.permissions)?;
+    if has_role(ctx, identity, role_id) {
+        return Err(format!("User already has the {} role", role.name));
+    }
+    record(ctx, AuditAction::GrantRole, Some(identity), "", role.name);
+    ctx.db.user_role().insert(UserRole {
+        id: 0,
+        identity,
+        role_id,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Role managers invoke this reducer to take a role away from a user.
+pub fn revoke_role(ctx: &ReducerContext, identity: Identity, role_id: u64) -> Result<(), String> {
+    require_permission(ctx, MANAGE_ROLES)?;
+    let role = find_role(ctx, role_id)?;
+    check_permission_bits(ctx, role.permissions)?;
+    if !has_role(ctx, identity, role_id) {
+        return Err(format!("User does not have the {} role", role.name));
+    }
+    if role.name == OWNER_ROLE && ctx.db.user_role().role_id().filter(role_id).count() == 1 {
+        return Err("Cannot revoke the role of the last owner".to_string());
+    }
+    record(ctx, AuditAction::RevokeRole, Some(identity), "", role.name);
+    ctx.db
+        .user_role()
+        .identity_and_role()
+        .delete((identity, role_id));
+    Ok(())
+}
+
+/// Creates the built-in roles and makes the caller the owner.
+pub fn init_roles(ctx: &ReducerContext) {
+    let owner = ctx.db.role().insert(Role {
+        id: 0,
+        name: OWNER_ROLE.to_string(),
+        permissions: ALL_PERMISSIONS,
+    });
+    ctx.db.role().insert(Role {
+        id: 0,
+        name: "moderator".to_string(),
+        permissions: SEND | DELETE_ANY | MUTE | BAN,
+    });
+    ctx.db.role().insert(Role {
+        id: 0,
+        name: MEMBER_ROLE.to_string(),
+        permissions: SEND | CREATE_CHANNELS,
+    });
+    ctx.db.user_role().insert(UserRole {
+        id: 0,
+        identity: ctx.sender,
+        role_id: owner.id,
+    });
+}
+
+/// Makes the roles `identity` holds match `role_ids`, for a trusted service keeping them in step.
+/// Roles that can't be synced, such as the owner role, are never granted or revoked.
+pub fn sync_roles_of(
+    ctx: &ReducerContext,
+    identity: Identity,
+    role_ids: &[u64],
+) -> Result<(), String> {
+    let roles = role_ids
+        .iter()
+        .map(|role_id| find_role(ctx, *role_id))
+        .collect::<Result<Vec<Role>, String>>()?;
+    if let Some(role) = roles.iter().find(|role| !is_syncable(role)) {
+        return Err(format!("The {} role cannot be synced", role.name));
+    }
+    let held: Vec<UserRole> = ctx
+        .db
+        .user_role()
+        .identity_and_role()
+        .filter(identity)
+        .collect();
+    for user_role in held {
+        if role_ids.contains(&user_role.role_id) {
+            continue;
+        }
+        if let Some(role) = ctx
+            .db
+            .role()
+            .id()
+            .find(user_role.role_id)
+            .filter(is_syncable)
+        {
+            record(ctx, AuditAction::RevokeRole, Some(identity), "", role.name);
+            ctx.db.user_role().id().delete(user_role.id);
+        }
+    }
+    for role in roles {
+        if !has_role(ctx, identity, role.id) {
+            record(ctx, AuditAction::GrantRole, Some(identity), "", role.name);
+            ctx.db.user_role().insert(UserRole {
+                id: 0,
+                identity,
+                role_id: role.id,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Every permission `identity` has, through its roles or the member role.
+/// Reducers pass `ctx.as_read_only()`, so reducers and views share this helper.
+pub fn permissions_of(ctx: &ViewContext, identity: Identity) -> u32 {
+    let db = &ctx.db;
+    let member = db
+        .role()
+        .name()
+        .find(MEMBER_ROLE.to_string())
+        .map_or(0, |role| role.permissions);
+    db.user_role()
+        .identity_and_role()
+        .filter(identity)
+        .filter_map(|user_role| db.role().id().find(user_role.role_id))
+        .fold(member, |permissions, role| permissions | role.permissions)
+}
+
+pub fn has_permission(ctx: &ReducerContext, identity: Identity, permission: u32) -> bool {
+    permissions_of(&ctx.as_read_only(), identity) & permission == permission
+}
+
+/// Fails unless the caller has `permission`.
+pub fn require_permission(ctx: &ReducerContext, permission: u32) -> Result<(), String> {
+    if has_permission(ctx, ctx.sender, permission) {
+        Ok(())
+    } else {
+        Err(format!(
+            "You need the {} permission to do that",
+            permission_name(permission)
+        ))
+    }
+}
+
+fn permission_name(permission: u32) -> &'static str {
+    match permission {
+        SEND => "send",
+        DELETE_ANY => "delete_any",
+        MUTE => "mute",
+        BAN => "ban",
+        MANAGE_CHANNELS => "manage_channels",
+        MANAGE_ROLES => "manage_roles",
+        MANAGE_SETTINGS => "manage_settings",
+        CREATE_CHANNELS => "create_channels",
+        _ => "required",
+    }
+}
+
+/// Checks that `permissions` only has known bits, all of which the caller has themselves,
+/// so role managers can't hand out more than they have.
+fn check_permission_bits(ctx: &ReducerContext, permissions: u32) -> Result<(), String> {
+    if permissions & !ALL_PERMISSIONS != 0 {
+        return Err(format!(
+            "Unknown permission bits {:#x}",
+            permissions & !ALL_PERMISSIONS
+        ));
+    }
+    let missing = permissions & !permissions_of(&ctx.as_read_only(), ctx.sender);
+    if missing != 0 {
+        return Err("Cannot manage roles with permissions you don't have".to_string());
+    }
+    Ok(())
+}
+
+fn find_role(ctx: &ReducerContext, role_id: u64) -> Result<Role, String> {
+    ctx.db
+        .role()
+        .id()
+        .find(role_id)
+        .ok_or_else(|| format!("No role with id {}", role_id))
+}
+
+fn is_syncable(role: &Role) -> bool {
+    role.name != OWNER_ROLE
+        && role.name != MEMBER_ROLE
+        && role.permissions & UNSYNCABLE_PERMISSIONS == 0
+}
+
+fn has_role(ctx: &ReducerContext, identity: Identity, role_id: u64) -> bool {
+    ctx.db
+        .user_role()
+        .identity_and_role()
+        .filter((identity, role_id))
+        .next()
+        .is_some()
+}
+
+/// Takes a role name and checks if it's acceptable, normalizing it to lowercase.
+fn validate_role_name(name: String) -> Result<String, String> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        Err("Role names must not be empty".to_string())
+    } else if name.chars().count() > 32 {
+        Err("Role names must be at most 32 characters".to_string())
+    } else if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        Err("Role names may only contain letters, digits, '-' and '_'".to_string())
+    } else {
+        Ok(name)
+    }
+}
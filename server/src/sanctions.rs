@@ -0,0 +1,206 @@
+use spacetimedb::{
+    reducer, table, Identity, ReducerContext, SpacetimeType, Table, TimeDuration, Timestamp,
+};
+
+use crate::audit::{record, AuditAction};
+use crate::roles::{permissions_of, require_permission, BAN, MUTE};
+
+#[derive(SpacetimeType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SanctionKind {
+    /// Keeps a user from connecting, sending messages and changing their name.
+    Ban,
+    /// Keeps a user from sending messages.
+    Mute,
+}
+
+#[table(name = sanction, public)]
+pub struct Sanction {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    #[index(btree)]
+    pub target: Identity,
+    pub kind: SanctionKind,
+    pub reason: String,
+    pub issued_by: Identity,
+    pub issued_at: Timestamp,
+    /// When the sanction ends, or `None` if it lasts until it's lifted.
+    pub until: Option<Timestamp>,
+}
+
+#[reducer]
+/// Moderators invoke this reducer to ban a user, either until `until` or indefinitely.
+pub fn ban_user(
+    ctx: &ReducerContext,
+    identity: Identity,
+    reason: String,
+    until: Option<Timestamp>,
+) -> Result<(), String> {
+    require_permission(ctx, BAN)?;
+    check_can_sanction(ctx, identity)?;
+    if until.is_some_and(|until| until <= ctx.timestamp) {
+        return Err("Bans must end in the future".to_string());
+    }
+    issue(ctx, identity, SanctionKind::Ban, reason, until);
+    Ok(())
+}
+
+#[reducer]
+/// Moderators invoke this reducer to keep a user from sending messages for a while.
+pub fn mute_user(
+    ctx: &ReducerContext,
+    identity: Identity,
+    duration: TimeDuration,
+) -> Result<(), String> {
+    require_permission(ctx, MUTE)?;
+    check_can_sanction(ctx, identity)?;
+    if duration.to_micros() <= 0 {
+        return Err("Mutes must last a positive duration".to_string());
+    }
+    let until = ctx.timestamp + duration;
+    issue(
+        ctx,
+        identity,
+        SanctionKind::Mute,
+        String::new(),
+        Some(until),
+    );
+    Ok(())
+}
+
+#[reducer]
+/// Moderators invoke this reducer to lift every ban on a user.
+pub fn unban_user(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    require_permission(ctx, BAN)?;
+    lift(ctx, identity, SanctionKind::Ban)
+}
+
+#[reducer]
+/// Moderators invoke this reducer to lift every mute on a user.
+pub fn unmute_user(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    require_permission(ctx, MUTE)?;
+    lift(ctx, identity, SanctionKind::Mute)
+}
+
+/// Fails if `identity` is banned, describing the ban.
+pub fn check_not_banned(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    match active_sanction(ctx, identity, SanctionKind::Ban) {
+        Some(ban) => Err(describe(ctx, "banned", &ban)),
+        None => Ok(()),
+    }
+}
+
+/// Fails if `identity` is banned or muted, describing the sanction.
+pub fn check_can_send(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    check_not_banned(ctx, identity)?;
+    match active_sanction(ctx, identity, SanctionKind::Mute) {
+        Some(mute) => Err(describe(ctx, "muted", &mute)),
+        None => Ok(()),
+    }
+}
+
+/// Finds the sanction of `kind` on `identity` that lasts the longest, if any is in effect.
+fn active_sanction(
+    ctx: &ReducerContext,
+    identity: Identity,
+    kind: SanctionKind,
+) -> Option<Sanction> {
+    ctx.db
+        .sanction()
+        .target()
+        .filter(identity)
+        .filter(|sanction| sanction.kind == kind)
+        .filter(|sanction| sanction.until.is_none_or(|until| until > ctx.timestamp))
+        // `None` sorts before `Some`, so map it to the latest possible end.
+        .max_by_key(|sanction| {
+            sanction
+                .until
+                .map_or(i64::MAX, |until| until.to_micros_since_unix_epoch())
+        })
+}
+
+fn describe(ctx: &ReducerContext, state: &str, sanction: &Sanction) -> String {
+    let mut description = match sanction.until {
+        Some(until) => {
+            let micros = until
+                .time_duration_since(ctx.timestamp)
+                .map_or(0, |left| left.to_micros());
+            format!(
+                "You are {} for another {} minutes",
+                state,
+                (micros + 59_999_999) / 60_000_000
+            )
+        }
+        None => format!("You are {}", state),
+    };
+    if !sanction.reason.is_empty() {
+        description.push_str(&format!(": {}", sanction.reason));
+    }
+    description
+}
+
+/// Moderators can't sanction themselves or anyone with permissions they don't have.
+fn check_can_sanction(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    if identity == ctx.sender {
+        return Err("Cannot sanction yourself".to_string());
+    }
+    let view = ctx.as_read_only();
+    if permissions_of(&view, identity) & !permissions_of(&view, ctx.sender) != 0 {
+        return Err("Cannot sanction a user with permissions you don't have".to_string());
+    }
+    Ok(())
+}
+
+fn issue(
+    ctx: &ReducerContext,
+    target: Identity,
+    kind: SanctionKind,
+    reason: String,
+    until: Option<Timestamp>,
+) {
+    let reason = reason.trim().to_string();
+    let action = match kind {
+        SanctionKind::Ban => AuditAction::Ban,
+        SanctionKind::Mute => AuditAction::Mute,
+    };
+    let details = match until {
+        Some(until) => format!("Until {}", until),
+        None => "Until lifted".to_string(),
+    };
+    record(ctx, action, Some(target), &reason, details);
+    ctx.db.sanction().insert(Sanction {
+        id: 0,
+        target,
+        kind,
+        reason,
+        issued_by: ctx.sender,
+        issued_at: ctx.timestamp,
+        until,
+    });
+}
+
+fn lift(ctx: &ReducerContext, identity: Identity, kind: SanctionKind) -> Result<(), String> {
+    if active_sanction(ctx, identity, kind).is_none() {
+        return Err(match kind {
+            SanctionKind::Ban => "User is not banned".to_string(),
+            SanctionKind::Mute => "User is not muted".to_string(),
+        });
+    }
+    let action = match kind {
+        SanctionKind::Ban => AuditAction::Unban,
+        SanctionKind::Mute => AuditAction::Unmute,
+    };
+    record(ctx, action, Some(identity), "", String::new());
+    // Expired sanctions of the same kind are removed along with the active ones.
+    let sanctions: Vec<Sanction> = ctx
+        .db
+        .sanction()
+        .target()
+        .filter(identity)
+        .filter(|sanction| sanction.kind == kind)
+        .collect();
+    for sanction in sanctions {
+        ctx.db.sanction().id().delete(sanction.id);
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use spacetimedb::{reducer, table, Identity, ReducerContext, Table, Timestamp};
+
+use crate::audit::{record, AuditAction};
+use crate::channels::find_channel;
+use crate::roles::{require_permission, sync_roles_of, MANAGE_SETTINGS};
+use crate::validation::validate_message;
+use crate::{post, user};
+
+/// Linking users to accounts they own with other providers.
+pub const LINK_ACCOUNTS: u32 = 1 << 0;
+/// Posting messages to any channel as the service.
+pub const POST_MESSAGES: u32 = 1 << 1;
+/// Granting and revoking roles to keep them in step with another community.
+pub const SYNC_ROLES: u32 = 1 << 2;
+pub const ALL_CAPABILITIES: u32 = LINK_ACCOUNTS | POST_MESSAGES | SYNC_ROLES;
+
+#[table(name = trusted_service, public)]
+/// Identities of backend helpers, such as the Discord login server,
+/// which may act on behalf of users.
+pub struct TrustedService {
+    #[primary_key]
+    identity: Identity,
+    name: String,
+    /// Bitset of the capability constants in this module.
+    capabilities: u32,
+    registered_by: Identity,
+    registered_at: Timestamp,
+}
+
+#[reducer]
+/// Admins invoke this reducer to let a backend helper take privileged actions,
+/// or to change which actions a registered helper may take.
+pub fn register_trusted_service(
+    ctx: &ReducerContext,
+    identity: Identity,
+    name: String,
+    capabilities: u32,
+) -> Result<(), String> {
+    require_permission(ctx, MANAGE_SETTINGS)?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Services need a name".to_string());
+    }
+    if capabilities & !ALL_CAPABILITIES != 0 {
+        return Err(format!(
+            "Unknown capability bits {:#x}",
+            capabilities & !ALL_CAPABILITIES
+        ));
+    }
+    record(
+        ctx,
+        AuditAction::RegisterService,
+        Some(identity),
+        "",
+        format!("{} with capabilities {:#x}", name, capabilities),
+    );
+    let service = TrustedService {
+        identity,
+        name,
+        capabilities,
+        registered_by: ctx.sender,
+        registered_at: ctx.timestamp,
+    };
+    if ctx.db.trusted_service().identity().find(identity).is_some() {
+        ctx.db.trusted_service().identity().update(service);
+    } else {
+        ctx.db.trusted_service().insert(service);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Admins invoke this reducer to stop trusting a backend helper.
+pub fn remove_trusted_service(ctx: &ReducerContext, identity: Identity) -> Result<(), String> {
+    require_permission(ctx, MANAGE_SETTINGS)?;
+    let service = ctx
+        .db
+        .trusted_service()
+        .identity()
+        .find(identity)
+        .ok_or_else(|| format!("{} is not a trusted service", identity))?;
+    record(
+        ctx,
+        AuditAction::RemoveService,
+        Some(identity),
+        "",
+        service.name,
+    );
+    ctx.db.trusted_service().identity().delete(identity);
+    Ok(())
+}
+
+#[reducer]
+/// Services invoke this reducer to announce something in a channel,
+/// without joining it or being held to rate limits.
+pub fn post_system_message(
+    ctx: &ReducerContext,
+    channel_id: u64,
+    text: String,
+) -> Result<(), String> {
+    require_capability(ctx, POST_MESSAGES)?;
+    let channel = find_channel(ctx, channel_id)?;
+    let text = validate_message(ctx, text)?;
+    log::info!("#{} (system): {}", channel.name, text);
+    post(ctx, channel, text, None);
+    Ok(())
+}
+
+#[reducer]
+/// Services invoke this reducer to make the roles a user holds match `role_ids`,
+/// e.g. the roles they have in a Discord server.
+pub fn sync_roles(
+    ctx: &ReducerContext,
+    identity: Identity,
+    role_ids: Vec<u64>,
+) -> Result<(), String> {
+    require_capability(ctx, SYNC_ROLES)?;
+    if ctx.db.user().identity().find(identity).is_none() {
+        return Err("Cannot sync roles of unknown user".to_string());
+    }
+    sync_roles_of(ctx, identity, &role_ids)
+}
+
+pub fn is_trusted_service(ctx: &ReducerContext, identity: Identity) -> bool {
+    ctx.db.trusted_service().identity().find(identity).is_some()
+}
+
+/// Fails unless the caller is a trusted service with `capability`.
+pub fn require_capability(ctx: &ReducerContext, capability: u32) -> Result<(), String> {
+    let capabilities = ctx
+        .db
+        .trusted_service()
+        .identity()
+        .find(ctx.sender)
+        .map_or(0, |service| service.capabilities);
+    if capabilities & capability == capability {
+        Ok(())
+    } else {
+        Err(format!(
+            "Only trusted services with the {} capability can do that",
+            capability_name(capability)
+        ))
+    }
+}
+
+fn capability_name(capability: u32) -> &'static str {
+    match capability {
+        LINK_ACCOUNTS => "link_accounts",
+        POST_MESSAGES => "post_messages",
+        SYNC_ROLES => "sync_roles",
+        _ => "required",
+    }
+}
@@ -0,0 +1,118 @@
+use spacetimedb::{
+    reducer, table, view, Identity, ReducerContext, ScheduleAt, Table, TimeDuration, Timestamp,
+    ViewContext,
+};
+
+use crate::channels::{find_channel, is_member, joined_channel_ids};
+use crate::sanctions::check_can_send;
+
+/// How long a typing indicator lasts unless the client refreshes it: 5 seconds.
+const TYPING_TIMEOUT_MICROS: i64 = 5_000_000;
+
+#[table(
+    name = typing,
+    index(name = identity_and_channel, btree(columns = [identity, channel_id]))
+)]
+/// Users currently composing a message in a channel, served by `my_channel_typing`.
+pub struct Typing {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    identity: Identity,
+    #[index(btree)]
+    channel_id: u64,
+    /// The indicator is removed at this time unless it's refreshed first.
+    expires_at: Timestamp,
+}
+
+#[table(name = typing_expiry, scheduled(expire_typing))]
+pub struct TypingExpiry {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+    identity: Identity,
+    channel_id: u64,
+}
+
+#[view(name = my_channel_typing, public)]
+/// Users typing in the channels the calling identity is a member of.
+pub fn my_channel_typing(ctx: &ViewContext) -> Vec<Typing> {
+    joined_channel_ids(ctx, ctx.sender)
+        .flat_map(|channel_id| ctx.db.typing().channel_id().filter(channel_id))
+        .collect()
+}
+
+#[reducer]
+/// Clients invoke this reducer while the user types in a channel, and with `false` once they stop.
+pub fn set_typing(ctx: &ReducerContext, channel_id: u64, typing: bool) -> Result<(), String> {
+    if !typing {
+        stop_typing(ctx, ctx.sender, channel_id);
+        return Ok(());
+    }
+    check_can_send(ctx, ctx.sender)?;
+    let channel = find_channel(ctx, channel_id)?;
+    if !is_member(ctx, channel_id, ctx.sender) {
+        return Err(format!("Join #{} before typing in it", channel.name));
+    }
+    let expires_at = ctx.timestamp + TimeDuration::from_micros(TYPING_TIMEOUT_MICROS);
+    match find_typing(ctx, ctx.sender, channel_id) {
+        Some(typing) => {
+            ctx.db.typing().id().update(Typing {
+                expires_at,
+                ..typing
+            });
+        }
+        None => {
+            ctx.db.typing().insert(Typing {
+                id: 0,
+                identity: ctx.sender,
+                channel_id,
+                expires_at,
+            });
+        }
+    }
+    ctx.db.typing_expiry().insert(TypingExpiry {
+        scheduled_id: 0,
+        scheduled_at: expires_at.into(),
+        identity: ctx.sender,
+        channel_id,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Called by the `typing_expiry` schedule to remove indicators that weren't refreshed in time.
+pub fn expire_typing(ctx: &ReducerContext, expiry: TypingExpiry) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("Only the module itself can expire typing indicators".to_string());
+    }
+    // Refreshing an indicator schedules another expiry instead of moving this one.
+    if let Some(typing) = find_typing(ctx, expiry.identity, expiry.channel_id) {
+        if typing.expires_at <= ctx.timestamp {
+            ctx.db.typing().id().delete(typing.id);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the typing indicator of `identity` in a channel, if it has one.
+pub fn stop_typing(ctx: &ReducerContext, identity: Identity, channel_id: u64) {
+    ctx.db
+        .typing()
+        .identity_and_channel()
+        .delete((identity, channel_id));
+}
+
+/// Removes every typing indicator of `identity`, such as when it disconnects.
+pub fn clear_typing(ctx: &ReducerContext, identity: Identity) {
+    ctx.db.typing().identity_and_channel().delete(identity);
+}
+
+fn find_typing(ctx: &ReducerContext, identity: Identity, channel_id: u64) -> Option<Typing> {
+    ctx.db
+        .typing()
+        .identity_and_channel()
+        .filter((identity, channel_id))
+        .next()
+}
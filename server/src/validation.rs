@@ -0,0 +1,299 @@
+use std::fmt;
+
+use spacetimedb::{reducer, table, view, Identity, ReducerContext, Table, Timestamp, ViewContext};
+use unicode_properties::{GeneralCategory, UnicodeGeneralCategory};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::audit::{record, AuditAction};
+use crate::roles::{permissions_of, require_permission, MANAGE_SETTINGS};
+use crate::user;
+
+const RULES_ID: u8 = 0;
+
+/// Invisible format characters that are allowed in messages, out of those that can hide
+/// or reorder text such as U+202E. Zero-width joiners and non-joiners are allowed,
+/// since emoji sequences and some scripts rely on them.
+const ALLOWED_FORMAT: [char; 2] = ['\u{200C}', '\u{200D}'];
+
+#[table(name = message_rules, public)]
+/// Module-wide limits that every message is checked against, stored as a single row.
+pub struct MessageRules {
+    #[primary_key]
+    id: u8,
+    pub max_bytes: u32,
+    /// Maximum length in user-perceived characters, so an emoji counts once.
+    pub max_graphemes: u32,
+    /// Longer runs of blank lines are collapsed down to this many.
+    pub max_blank_lines: u32,
+    /// Whether users must set a name before they can send messages.
+    pub require_name: bool,
+}
+
+impl Default for MessageRules {
+    fn default() -> Self {
+        Self {
+            id: RULES_ID,
+            max_bytes: 4000,
+            max_graphemes: 1000,
+            max_blank_lines: 1,
+            require_name: false,
+        }
+    }
+}
+
+#[table(name = banned_word)]
+/// Words that messages must not contain, matched case-insensitively as whole words.
+pub struct BannedWord {
+    #[primary_key]
+    word: String,
+    #[index(btree)]
+    added_by: Identity,
+    added_at: Timestamp,
+}
+
+#[view(name = banned_words, public)]
+/// Every banned word for settings managers, who maintain the list, and nothing for anyone else.
+pub fn banned_words(ctx: &ViewContext) -> Vec<BannedWord> {
+    if permissions_of(ctx, ctx.sender) & MANAGE_SETTINGS == 0 {
+        return Vec::new();
+    }
+    // Views can't scan whole tables, so take every word through the `added_by` index instead.
+    ctx.db
+        .banned_word()
+        .added_by()
+        .filter(Identity::ZERO..)
+        .collect()
+}
+
+/// Why a message was refused. Clients receive it as `[code] description`,
+/// where the code is stable and the description is meant for the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Empty,
+    TooManyBytes { max: u32 },
+    TooManyGraphemes { max: u32 },
+    ForbiddenCharacter(char),
+    BannedWord(String),
+    NameRequired,
+}
+
+impl Rejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::Empty => "empty",
+            Rejection::TooManyBytes { .. } => "too_many_bytes",
+            Rejection::TooManyGraphemes { .. } => "too_long",
+            Rejection::ForbiddenCharacter(_) => "forbidden_character",
+            Rejection::BannedWord(_) => "banned_word",
+            Rejection::NameRequired => "name_required",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Empty => write!(f, "Messages must not be empty"),
+            Rejection::TooManyBytes { max } => {
+                write!(f, "Messages must be at most {} bytes", max)
+            }
+            Rejection::TooManyGraphemes { max } => {
+                write!(f, "Messages must be at most {} characters", max)
+            }
+            Rejection::ForbiddenCharacter(c) => {
+                write!(
+                    f,
+                    "Messages must not contain the character U+{:04X}",
+                    *c as u32
+                )
+            }
+            Rejection::BannedWord(word) => write!(f, "Messages must not contain \"{}\"", word),
+            Rejection::NameRequired => write!(f, "Set a name before sending messages"),
+        }
+    }
+}
+
+impl From<Rejection> for String {
+    fn from(rejection: Rejection) -> Self {
+        format!("[{}] {}", rejection.code(), rejection)
+    }
+}
+
+#[reducer]
+/// Settings managers invoke this reducer to change the limits messages are checked against.
+pub fn configure_message_rules(
+    ctx: &ReducerContext,
+    max_bytes: u32,
+    max_graphemes: u32,
+    max_blank_lines: u32,
+    require_name: bool,
+) -> Result<(), String> {
+    require_permission(ctx, MANAGE_SETTINGS)?;
+    if max_bytes == 0 || max_graphemes == 0 {
+        return Err("Message length limits must be at least 1".to_string());
+    }
+    let rules = MessageRules {
+        id: RULES_ID,
+        max_bytes,
+        max_graphemes,
+        max_blank_lines,
+        require_name,
+    };
+    record(
+        ctx,
+        AuditAction::ChangeSettings,
+        None,
+        "",
+        format!(
+            "Message rules: at most {} bytes, {} characters, {} blank lines, name required: {}",
+            max_bytes, max_graphemes, max_blank_lines, require_name
+        ),
+    );
+    if ctx.db.message_rules().id().find(RULES_ID).is_some() {
+        ctx.db.message_rules().id().update(rules);
+    } else {
+        ctx.db.message_rules().insert(rules);
+    }
+    Ok(())
+}
+
+#[reducer]
+/// Settings managers invoke this reducer to stop messages containing a word from being sent.
+pub fn add_banned_word(ctx: &ReducerContext, word: String) -> Result<(), String> {
+    require_permission(ctx, MANAGE_SETTINGS)?;
+    let word = validate_banned_word(word)?;
+    if ctx.db.banned_word().word().find(&word).is_some() {
+        return Err(format!("\"{}\" is already banned", word));
+    }
+    record(
+        ctx,
+        AuditAction::ChangeSettings,
+        None,
+        "",
+        format!("Banned \"{}\"", word),
+    );
+    ctx.db.banned_word().insert(BannedWord {
+        word,
+        added_by: ctx.sender,
+        added_at: ctx.timestamp,
+    });
+    Ok(())
+}
+
+#[reducer]
+/// Settings managers invoke this reducer to allow a banned word again.
+pub fn remove_banned_word(ctx: &ReducerContext, word: String) -> Result<(), String> {
+    require_permission(ctx, MANAGE_SETTINGS)?;
+    let word = word.trim().to_lowercase();
+    if ctx.db.banned_word().word().delete(&word) {
+        record(
+            ctx,
+            AuditAction::ChangeSettings,
+            None,
+            "",
+            format!("Unbanned \"{}\"", word),
+        );
+        Ok(())
+    } else {
+        Err(format!("\"{}\" is not banned", word))
+    }
+}
+
+pub fn init_rules(ctx: &ReducerContext) {
+    if ctx.db.message_rules().id().find(RULES_ID).is_none() {
+        ctx.db.message_rules().insert(MessageRules::default());
+    }
+}
+
+/// Fails if the module requires a name to send messages and the caller hasn't set one.
+pub fn check_name_set(ctx: &ReducerContext) -> Result<(), Rejection> {
+    let rules = ctx
+        .db
+        .message_rules()
+        .id()
+        .find(RULES_ID)
+        .unwrap_or_default();
+    let named = ctx
+        .db
+        .user()
+        .identity()
+        .find(ctx.sender)
+        .is_some_and(|user| user.name.is_some());
+    if rules.require_name && !named {
+        return Err(Rejection::NameRequired);
+    }
+    Ok(())
+}
+
+/// Takes a message and checks it against the module's rules,
+/// returning it trimmed and with excessive blank lines collapsed.
+pub fn validate_message(ctx: &ReducerContext, text: String) -> Result<String, Rejection> {
+    let rules = ctx
+        .db
+        .message_rules()
+        .id()
+        .find(RULES_ID)
+        .unwrap_or_default();
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    if let Some(c) = text.chars().find(|c| is_forbidden(*c)) {
+        return Err(Rejection::ForbiddenCharacter(c));
+    }
+    let text = collapse_blank_lines(text.trim(), rules.max_blank_lines);
+    if text.is_empty() {
+        return Err(Rejection::Empty);
+    }
+    if text.len() > rules.max_bytes as usize {
+        return Err(Rejection::TooManyBytes {
+            max: rules.max_bytes,
+        });
+    }
+    if text.graphemes(true).count() > rules.max_graphemes as usize {
+        return Err(Rejection::TooManyGraphemes {
+            max: rules.max_graphemes,
+        });
+    }
+    let banned = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .find(|word| ctx.db.banned_word().word().find(word).is_some());
+    if let Some(word) = banned {
+        return Err(Rejection::BannedWord(word));
+    }
+    Ok(text)
+}
+
+fn is_forbidden(c: char) -> bool {
+    (c.is_control() && c != '\n' && c != '\t')
+        || (c.general_category() == GeneralCategory::Format && !ALLOWED_FORMAT.contains(&c))
+}
+
+/// Strips trailing whitespace from every line and keeps at most `max` blank lines in a row.
+fn collapse_blank_lines(text: &str, max: u32) -> String {
+    let mut lines = Vec::new();
+    let mut blank_run = 0;
+    for line in text.lines().map(str::trim_end) {
+        if line.is_empty() {
+            blank_run += 1;
+            if blank_run > max {
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Takes a word to ban and normalizes it to how `validate_message` compares words.
+fn validate_banned_word(word: String) -> Result<String, String> {
+    let word = word.trim().to_lowercase();
+    if word.is_empty() {
+        Err("Banned words must not be empty".to_string())
+    } else if !word.chars().all(char::is_alphanumeric) {
+        Err("Banned words may only contain letters and digits".to_string())
+    } else {
+        Ok(word)
+    }
+}